@@ -0,0 +1,52 @@
+//! Times evaluation through native code against batch evaluation of the same program
+//! Run with cargo bench --features jit
+
+extern crate rand;
+extern crate symboreg;
+
+use std::hint::black_box;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use symboreg::{Context, Point, PrimitiveSet, SymbolicBinaryHeap};
+
+const POINTS: usize = 10_000;
+const REPEATS: u32 = 200;
+
+const EXPRESSIONS: [&str; 3] = [
+    "x0 * x0 * x1 - 2 * x0 + x1 / (1 + x0 * x0)",
+    "(x0 - 1.5) * (x1 + 0.5) / (x0 * x1 + 2) - x0 * (x1 - x0)",
+    "sin(x0) * x1 + cos(x0 * x1)"
+];
+
+/// Average time of f over REPEATS runs
+fn time<F: FnMut() -> Vec<f32>>(mut f: F) -> Duration {
+    let start = Instant::now();
+    for _ in 0..REPEATS {
+        black_box(f());
+    }
+    start.elapsed() / REPEATS
+}
+
+fn main() {
+    let context = Rc::new(Context::<f32>::new(2, PrimitiveSet::all()));
+    let mut rng = StdRng::seed_from_u64(1);
+    let points: Vec<Point<f32>> = (0..POINTS).map(|_| {
+        Point::new(vec![rng.gen_range(-3.0, 3.0), rng.gen_range(-3.0, 3.0)], 0.0)
+    }).collect();
+    println!("{} points, f32", POINTS);
+    for expression in EXPRESSIONS.iter() {
+        let heap = SymbolicBinaryHeap::parse(expression, &context).unwrap();
+        let program = heap.compile();
+        let start = Instant::now();
+        let function = heap.jit().unwrap();
+        let compiling = start.elapsed();
+        let batch = time(|| program.evaluate_points(&points));
+        let native = time(|| function.evaluate_points(&points));
+        println!("{}\n    compile {:?}, batch {:?}, native {:?}, {:.2}x",
+                 expression, compiling, batch, native,
+                 batch.as_secs_f64() / native.as_secs_f64());
+    }
+}
@@ -0,0 +1,93 @@
+//! Times evolving and evaluating a population in each tree representation, and reports
+//! the node slots each holds in memory
+//! Run with cargo bench --bench representations
+
+extern crate rand;
+extern crate symboreg;
+
+use std::hint::black_box;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use symboreg::{ArenaTree, Context, ExpressionTree, Point, PrimitiveSet, SymbolicBinaryHeap};
+
+const POPULATION: usize = 200;
+const GENERATIONS: usize = 50;
+const POINTS: usize = 1_000;
+const MAX_DEPTHS: [u32; 3] = [6, 10, 14];
+
+/// Mutates and crosses over every tree once per generation, failed operations included
+fn evolve<D: ExpressionTree<f64>>(population: &mut [D], rng: &mut StdRng) {
+    for _ in 0..GENERATIONS {
+        for i in 0..population.len() {
+            let tree = &mut population[i];
+            let _ = match rng.gen_range(0, 4) {
+                0 => tree.mutate_constant(rng),
+                1 => tree.mutate_insert(rng),
+                2 => tree.mutate_swap(rng),
+                _ => tree.mutate_similar(rng)
+            };
+            let j = rng.gen_range(0, population.len());
+            if i != j {
+                let mut other = population[j].clone();
+                let _ = population[i].cross_over(&mut other, rng);
+                population[j] = other;
+            }
+        }
+    }
+}
+
+/// Evaluates every tree at every point, through its own collapse and its compiled program
+fn evaluate<D: ExpressionTree<f64>>(population: &[D], points: &[Point<f64>])
+                                                              -> (Duration, Duration) {
+    let start = Instant::now();
+    for tree in population {
+        for point in points {
+            black_box(tree.collapse(&point.x));
+        }
+    }
+    let collapsing = start.elapsed();
+    let start = Instant::now();
+    for tree in population {
+        black_box(tree.compile().evaluate_points(points));
+    }
+    (collapsing, start.elapsed())
+}
+
+fn run<D: ExpressionTree<f64>>(name: &str, context: &Rc<Context<f64>>, points: &[Point<f64>]) {
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut population: Vec<D> = (0..POPULATION).map(|_| {
+        let mut tree = D::new(context);
+        tree.spawn(&mut rng).unwrap();
+        tree
+    }).collect();
+    let start = Instant::now();
+    evolve(&mut population, &mut rng);
+    let evolving = start.elapsed();
+    let (collapsing, batch) = evaluate(&population, points);
+    let mean = |f: &dyn Fn(&D) -> usize| {
+        population.iter().map(f).sum::<usize>() as f64 / population.len() as f64
+    };
+    println!("    {:5} evolve {:?}, collapse {:?}, batch {:?}, mean slots {:.1}, \
+              nodes {:.1}, depth {:.1}",
+             name, evolving, collapsing, batch, mean(&|tree| tree.slots()),
+             mean(&|tree| tree.complexity() as usize), mean(&|tree| tree.depth() as usize));
+}
+
+fn main() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let points: Vec<Point<f64>> = (0..POINTS).map(|_| {
+        Point::new(vec![rng.gen_range(-3.0, 3.0), rng.gen_range(-3.0, 3.0)], 0.0)
+    }).collect();
+    println!("{} trees, {} generations, {} points, f64", POPULATION, GENERATIONS, POINTS);
+    for &max_depth in MAX_DEPTHS.iter() {
+        let mut context = Context::new(2, PrimitiveSet::all());
+        context.max_depth = max_depth;
+        let context = Rc::new(context);
+        println!("max_depth {}", max_depth);
+        run::<SymbolicBinaryHeap<f64>>("heap", &context, &points);
+        run::<ArenaTree<f64>>("arena", &context, &points);
+    }
+}
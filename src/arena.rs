@@ -0,0 +1,778 @@
+extern crate rand;
+
+use std::fmt;
+use std::rc::Rc;
+use self::rand::Rng;
+
+use super::{Context, Expr, ExpressionTree, Float, HeapError, Node, Point, Program};
+use sbh::{infix, length_from_depth};
+
+/// A node of an ArenaTree with the indices of its operands
+/// Unary operators keep theirs in the first slot
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArenaNode<T> {
+    pub node: Node<T>,
+    pub children: [Option<usize>; 2]
+}
+
+impl<T> ArenaNode<T> {
+    pub fn new(node: Node<T>) -> Self {
+        ArenaNode {node, children: [None, None]}
+    }
+}
+
+/// An expression tree kept as nodes in one vector, each holding the indices of its operands
+/// Memory grows with the number of nodes rather than with 2^max_depth as in SymbolicBinaryHeap,
+/// so deep and unbalanced trees stay affordable: operations cap the number of nodes rather
+/// than the depth, see max_nodes
+#[derive(Debug, Clone)]
+pub struct ArenaTree<T> {
+    /// Nodes in preorder with the root first, so every subtree is a contiguous run
+    pub nodes: Vec<ArenaNode<T>>,
+    pub context: Rc<Context<T>>
+}
+
+/// A subtree at target to be replaced by the one at source_idx of source
+type Graft<'a, T> = (usize, &'a [ArenaNode<T>], usize);
+
+/// Copies the subtree at idx of nodes to the end of out in preorder, returning its new index
+/// Walks an explicit stack rather than recursing, so trees of any depth are copied
+fn copy<T: Copy>(nodes: &[ArenaNode<T>], idx: usize, graft: Option<Graft<T>>,
+                 out: &mut Vec<ArenaNode<T>>) -> usize {
+    let root = out.len();
+    // Subtrees left to copy, each with the graft still to make within it and the operand
+    // slot of the copied parent to point at it
+    let mut stack = vec![(nodes, idx, graft, None::<(usize, usize)>)];
+    while let Some((nodes, idx, graft, slot)) = stack.pop() {
+        let (nodes, idx, graft) = match graft {
+            Some((target, source, source_idx)) if idx == target => (source, source_idx, None),
+            _ => (nodes, idx, graft)
+        };
+        let position = out.len();
+        out.push(ArenaNode::new(nodes[idx].node));
+        if let Some((parent, side)) = slot {
+            out[parent].children[side] = Some(position);
+        }
+        for (side, child) in nodes[idx].children.iter().enumerate().rev() {
+            if let Some(child) = *child {
+                stack.push((nodes, child, graft, Some((position, side))));
+            }
+        }
+    }
+    root
+}
+
+/// Appends an expression to out in preorder, returning the index of its root
+fn push_expr<T: Float>(expr: &Expr<T>, out: &mut Vec<ArenaNode<T>>) -> usize {
+    let idx = out.len();
+    out.push(ArenaNode::new(expr.node()));
+    match expr {
+        Expr::Terminal(_) => (),
+        Expr::Unary(_, operand) => out[idx].children[0] = Some(push_expr(operand, out)),
+        Expr::Binary(_, left, right) => {
+            out[idx].children[0] = Some(push_expr(left, out));
+            out[idx].children[1] = Some(push_expr(right, out)); }
+    }
+    idx
+}
+
+/// Appends a random subtree of depth levels to out, returning the index of its root
+/// Below each operator one operand is an operator and the other is one half of the time,
+/// as in SymbolicBinaryHeap::random_instantiate, down to a last level of terminals
+fn random_subtree<T: Float, R: Rng>(context: &Context<T>, depth: u32, rng: &mut R,
+                                    out: &mut Vec<ArenaNode<T>>) -> usize {
+    let idx = out.len();
+    if depth < 2 {
+        out.push(ArenaNode::new(context.random_terminal(rng)));
+        return idx;
+    }
+    let op = context.random_op(rng);
+    out.push(ArenaNode::new(op));
+    let arity = context.primitives.arity(op);
+    let op_side = rng.gen_range(0, arity);
+    for side in 0..arity {
+        let grow = depth > 2 && (side == op_side || rng.gen());
+        let child = random_subtree(context, if grow {depth - 1} else {1}, rng, out);
+        out[idx].children[side] = Some(child);
+    }
+    idx
+}
+
+/// Appends a random subtree of at most depth levels to out, returning the index of its root
+/// Full trees have operators on every level above the last, others pick operators or
+/// terminals with equal odds below the root, as in SymbolicBinaryHeap::random_full and grow
+fn random_fill<T: Float, R: Rng>(context: &Context<T>, depth: u32, full: bool, rng: &mut R,
+                                 out: &mut Vec<ArenaNode<T>>) -> usize {
+    let idx = out.len();
+    let use_op = depth > 1 && (idx == 0 || full || rng.gen());
+    let node = if use_op {context.random_op(rng)} else {context.random_terminal(rng)};
+    out.push(ArenaNode::new(node));
+    for side in 0..context.primitives.arity(node) {
+        let child = random_fill(context, depth - 1, full, rng, out);
+        out[idx].children[side] = Some(child);
+    }
+    idx
+}
+
+impl<T: Float> ArenaTree<T> {
+    /// Creates an empty tree sharing the simulation's context
+    pub fn new(context: &Rc<Context<T>>) -> Self {
+        ArenaTree {nodes: Vec::new(), context: Rc::clone(context)}
+    }
+
+    /// Lays out an expression tree, which unlike SymbolicBinaryHeap::from_expr
+    /// may be deeper than max_depth
+    pub fn from_expr(expr: &Expr<T>, context: &Rc<Context<T>>) -> Self {
+        let mut nodes: Vec<ArenaNode<T>> = Vec::new();
+        push_expr(expr, &mut nodes);
+        ArenaTree {nodes, context: Rc::clone(context)}
+    }
+
+    /// Returns the expression tree, missing operands becoming the 0 they evaluate to
+    pub fn to_expr(&self) -> Expr<T> {
+        self.expr_at_idx(Some(0))
+    }
+
+    fn expr_at_idx(&self, idx: Option<usize>) -> Expr<T> {
+        let arena_node = match idx.and_then(|idx| self.nodes.get(idx)) {
+            Some(arena_node) => *arena_node,
+            None => return Expr::number(T::ZERO)
+        };
+        let [left, right] = arena_node.children;
+        match self.context.primitives.arity(arena_node.node) {
+            0 => Expr::Terminal(arena_node.node),
+            1 => Expr::unary(arena_node.node, self.expr_at_idx(left)),
+            _ => Expr::binary(arena_node.node, self.expr_at_idx(left), self.expr_at_idx(right))
+        }
+    }
+
+    /// Depth of the random trees spawned, bounding the subtrees grown by mutation
+    /// The tree itself may grow deeper through mutation and cross over
+    pub fn max_depth(&self) -> u32 {
+        self.context.max_depth
+    }
+
+    /// Most nodes mutation and cross over grow a tree to, the slots of a SymbolicBinaryHeap
+    /// of max_depth, so trees trade breadth for depth but take no more memory
+    pub fn max_nodes(&self) -> usize {
+        length_from_depth(self.max_depth())
+    }
+
+    /// Depth of the subtree at every node, children coming after their parent in preorder
+    fn heights(&self) -> Vec<u32> {
+        let mut heights: Vec<u32> = vec![1; self.nodes.len()];
+        for idx in (0..self.nodes.len()).rev() {
+            for &child in self.nodes[idx].children.iter().flatten() {
+                heights[idx] = heights[idx].max(heights[child] + 1);
+            }
+        }
+        heights
+    }
+
+    /// Number of nodes in the subtree at every node
+    fn sizes(&self) -> Vec<usize> {
+        let mut sizes: Vec<usize> = vec![1; self.nodes.len()];
+        for idx in (0..self.nodes.len()).rev() {
+            for &child in self.nodes[idx].children.iter().flatten() {
+                sizes[idx] += sizes[child];
+            }
+        }
+        sizes
+    }
+
+    /// Returns the depth of the deepest node, 0 for an empty tree
+    pub fn depth(&self) -> u32 {
+        self.heights().first().copied().unwrap_or(0)
+    }
+
+    /// Here, complexity is defined as the number of nodes in the tree
+    pub fn complexity(&self) -> u32 {
+        self.nodes.len() as u32
+    }
+
+    fn get_terminal_idxs(&self) -> Vec<usize> {
+        (0..self.nodes.len()).filter(|&idx| self.is_terminal(idx)).collect()
+    }
+
+    fn get_op_idxs(&self) -> Vec<usize> {
+        (0..self.nodes.len()).filter(|&idx| !self.is_terminal(idx)).collect()
+    }
+
+    fn is_terminal(&self, idx: usize) -> bool {
+        matches!(self.nodes[idx].node, Node::Variable(_) | Node::Number(_))
+    }
+
+    /// Copies the tree back into preorder, with the subtree at the graft's target replaced
+    fn rebuild(&mut self, graft: Option<Graft<T>>) {
+        let mut nodes: Vec<ArenaNode<T>> = Vec::with_capacity(self.nodes.len());
+        copy(&self.nodes, 0, graft, &mut nodes);
+        self.nodes = nodes;
+    }
+
+    /// Replaces the subtree at idx with the one at source_idx of source
+    fn graft(&mut self, idx: usize, source: &[ArenaNode<T>], source_idx: usize) {
+        self.rebuild(Some((idx, source, source_idx)));
+    }
+
+    /// Returns the parent of idx and which of its operand slots points at idx
+    fn slot_of(&self, idx: usize) -> Result<(usize, usize), HeapError> {
+        self.nodes.iter().enumerate().find_map(|(parent_idx, arena_node)| {
+            arena_node.children.iter().position(|&child| child == Some(idx))
+                .map(|side| (parent_idx, side))
+        }).ok_or(HeapError::Orphan(idx))
+    }
+
+    /// Construct a node for idx with a variable or a constant of the node solved at 1
+    /// The constant is kept in range, see Context::bound_constant
+    fn op_to_terminal<R: Rng>(&self, idx: usize, rng: &mut R) -> Node<T> {
+        let use_variable: bool = rng.gen();
+        if use_variable {
+            Node::Variable(rng.gen_range(0, self.context.variables))
+        } else {
+            let num = self._collapse(idx, &vec![T::ONE; self.context.variables]);
+            Node::Number(self.context.bound_constant(num, rng))
+        }
+    }
+
+    pub fn has_variable(&self) -> bool {
+        self.nodes.iter().any(|arena_node| matches!(arena_node.node, Node::Variable(_)))
+    }
+
+    /// Turns a random constant into a variable if the tree has no variable
+    /// Returns whether anything changed
+    pub fn ensure_variable<R: Rng>(&mut self, rng: &mut R) -> bool {
+        if self.has_variable() {
+            return false;
+        }
+        let constants: Vec<usize> = (0..self.nodes.len())
+            .filter(|&idx| matches!(self.nodes[idx].node, Node::Number(_)))
+            .collect();
+        if constants.is_empty() {
+            return false;
+        }
+        let choice = constants[rng.gen_range(0, constants.len())];
+        self.nodes[choice].node = Node::Variable(rng.gen_range(0, self.context.variables));
+        true
+    }
+
+    /// Replaces the tree with one where every branch reaches depth, fails if depth is below 2
+    /// Unlike SymbolicBinaryHeap::random_full, depth may exceed max_depth
+    pub fn random_full<R: Rng>(&mut self, depth: u32, rng: &mut R) -> Result<(), HeapError> {
+        self.random_fill(depth, true, rng)
+    }
+
+    /// Replaces the tree with one of at most depth levels and an operator at the root,
+    /// fails if depth is below 2
+    pub fn random_grow<R: Rng>(&mut self, depth: u32, rng: &mut R) -> Result<(), HeapError> {
+        self.random_fill(depth, false, rng)
+    }
+
+    fn random_fill<R: Rng>(&mut self, depth: u32, full: bool, rng: &mut R)
+                                                          -> Result<(), HeapError> {
+        if depth < 2 {
+            return Err(HeapError::BadDepth {idx: 0, depth});
+        }
+        self.nodes.clear();
+        random_fill(&self.context, depth, full, rng, &mut self.nodes);
+        Ok(())
+    }
+
+    /// Operations keep a tree valid, so only an empty one needs repair, getting a random
+    /// one of depth 2
+    /// Returns whether anything changed
+    pub fn repair<R: Rng>(&mut self, rng: &mut R) -> Result<bool, HeapError> {
+        if !self.nodes.is_empty() {
+            return Ok(false);
+        }
+        self.random_grow(2, rng)?;
+        Ok(true)
+    }
+
+    /// Replaces the tree with a random one, which fails if max_depth is below 3
+    pub fn spawn<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        if self.max_depth() < 3 {
+            return Err(HeapError::BadDepth {idx: 0, depth: self.max_depth()});
+        }
+        let depth: u32 = rng.gen_range(2, self.max_depth());
+        self.nodes.clear();
+        random_subtree(&self.context, depth, rng, &mut self.nodes);
+        Ok(())
+    }
+
+    /// Alters a random constant half of the time, else grows a random terminal into a subtree
+    /// of depth below max_depth wherever it is, as the tree has no depth to stay within
+    /// A constant is altered instead when the subtree would take the tree past max_nodes
+    pub fn mutate_constant<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        if self.nodes.is_empty() {
+            return Err(HeapError::Empty);
+        }
+        let terminals = self.get_terminal_idxs();
+        if terminals.is_empty() {
+            return Err(HeapError::NoCandidates);
+        }
+        let choice = terminals[rng.gen_range(0, terminals.len())];
+        if !matches!(self.nodes[choice].node, Node::Number(_)) || rng.gen() {
+            let mut subtree: Vec<ArenaNode<T>> = Vec::new();
+            let spawn_depth = rng.gen_range(2, self.max_depth().max(3));
+            random_subtree(&self.context, spawn_depth, rng, &mut subtree);
+            if self.nodes.len() - 1 + subtree.len() <= self.max_nodes() {
+                self.graft(choice, &subtree, 0);
+                return Ok(());
+            }
+        }
+        let constants: Vec<usize> = terminals.into_iter()
+            .filter(|&idx| matches!(self.nodes[idx].node, Node::Number(_)))
+            .collect();
+        if constants.is_empty() {
+            return Err(HeapError::NoCandidates);
+        }
+        let choice = constants[rng.gen_range(0, constants.len())];
+        if let Node::Number(n) = self.nodes[choice].node {
+            self.nodes[choice].node = Node::Number(self.context.perturb_constant(n, rng));
+        }
+        Ok(())
+    }
+
+    /// Replace a random operation node below the root with a terminal node
+    /// A tree with no such operator is replaced by a random one of depth 3
+    pub fn mutate_clip<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        let ops: Vec<usize> = self.get_op_idxs().into_iter().filter(|&idx| idx > 0).collect();
+        if ops.is_empty() {
+            self.nodes.clear();
+            random_subtree(&self.context, 3, rng, &mut self.nodes);
+            return Ok(());
+        }
+        let choice = ops[rng.gen_range(0, ops.len())];
+        let terminal = ArenaNode::new(self.op_to_terminal(choice, rng));
+        self.graft(choice, &[terminal], 0);
+        Ok(())
+    }
+
+    /// Swap two random unrelated branches, only repointing their parents' operand slots
+    /// Falls back to mutate_constant when every other branch contains or is within the first
+    pub fn mutate_swap<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        if self.nodes.len() < 3 { // No two branches below the root
+            return self.mutate_constant(rng);
+        }
+        let sizes = self.sizes();
+        let contains = |a: usize, b: usize| a <= b && b < a + sizes[a];
+        let mut candidates: Vec<usize> = (1..self.nodes.len()).collect();
+        let choice1 = candidates.swap_remove(rng.gen_range(0, candidates.len()));
+        while !candidates.is_empty() {
+            let choice2 = candidates.swap_remove(rng.gen_range(0, candidates.len()));
+            if !contains(choice1, choice2) && !contains(choice2, choice1) {
+                let (parent1, side1) = self.slot_of(choice1)?;
+                let (parent2, side2) = self.slot_of(choice2)?;
+                self.nodes[parent1].children[side1] = Some(choice2);
+                self.nodes[parent2].children[side2] = Some(choice1);
+                self.rebuild(None);
+                return Ok(());
+            }
+        }
+        self.mutate_constant(rng)
+    }
+
+    /// Replace a random operation node with its similar operation
+    /// Variables are swapped for another available variable, and constants altered
+    pub fn mutate_similar<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        let variables = self.context.variables;
+        let mut candidates: Vec<(usize, Node<T>)> = Vec::new();
+        for (idx, arena_node) in self.nodes.iter().enumerate() {
+            match arena_node.node {
+                Node::Number(n) => candidates.push((idx, Node::Number(n))),
+                Node::Variable(v) if variables > 1 => candidates.push((idx, Node::Variable(v))),
+                Node::Variable(_) => (),
+                op => if let Some(similar) = self.context.primitives.similar(op) {
+                    candidates.push((idx, similar))
+                }
+            };
+        }
+        if candidates.is_empty() { // Nothing has an enabled partner
+            return self.mutate_constant(rng);
+        }
+        let (idx, node) = candidates[rng.gen_range(0, candidates.len())];
+        self.nodes[idx].node = match node {
+            Node::Number(n) => Node::Number(self.context.perturb_constant(n, rng)),
+            Node::Variable(v) => Node::Variable((v + rng.gen_range(1, variables)) % variables),
+            op => op
+        };
+        Ok(())
+    }
+
+    /// Change a random terminal node in place, as SymbolicBinaryHeap::mutate_terminal does
+    pub fn mutate_terminal<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        let terminals: Vec<usize> = self.get_terminal_idxs();
+        if terminals.is_empty() {
+            return Err(HeapError::NoCandidates);
+        }
+        let choice = terminals[rng.gen_range(0, terminals.len())];
+        let variables = self.context.variables;
+        self.nodes[choice].node = match self.nodes[choice].node {
+            Node::Variable(v) => if variables > 1 && rng.gen() {
+                Node::Variable((v + rng.gen_range(1, variables)) % variables)
+            } else {
+                Node::Number(self.context.random_constant(rng))
+            },
+            Node::Number(n) => if rng.gen() {
+                Node::Variable(rng.gen_range(0, variables))
+            } else {
+                Node::Number(self.context.jitter_constant(n, rng))
+            },
+            _ => return Err(HeapError::BadIndex(choice))
+        };
+        Ok(())
+    }
+
+    /// Replace the tree with the subtree of a random operation node below the root
+    pub fn mutate_hoist<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        let ops: Vec<usize> = self.get_op_idxs();
+        if ops.len() < 2 { // Hoisting a terminal would leave nothing to operate on
+            return self.mutate_constant(rng);
+        }
+        let choice = ops[rng.gen_range(1, ops.len())];
+        let mut nodes: Vec<ArenaNode<T>> = Vec::new();
+        copy(&self.nodes, choice, None, &mut nodes);
+        self.nodes = nodes;
+        Ok(())
+    }
+
+    /// Replace the subtree of a random operation node below the root with one of its terminals
+    pub fn mutate_shrink<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        let ops: Vec<usize> = self.get_op_idxs();
+        if ops.len() < 2 {
+            return self.mutate_constant(rng);
+        }
+        let choice = ops[rng.gen_range(1, ops.len())];
+        let size = self.sizes()[choice];
+        let terminals: Vec<usize> = (choice..choice + size)
+            .filter(|&idx| self.is_terminal(idx))
+            .collect();
+        if terminals.is_empty() {
+            return Err(HeapError::MissingOperand(choice));
+        }
+        let terminal = self.nodes[terminals[rng.gen_range(0, terminals.len())]];
+        self.graft(choice, &[terminal], 0);
+        Ok(())
+    }
+
+    /// Wrap the subtree of a random node in a new operation node, one level deeper
+    /// A binary operation gets a random terminal as its other operand
+    /// Falls back to mutate_constant when the new nodes would take the tree past max_nodes
+    pub fn mutate_insert<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        if self.nodes.is_empty() {
+            return Err(HeapError::Empty);
+        }
+        let choice = rng.gen_range(0, self.nodes.len());
+        let op = self.context.random_op(rng);
+        if self.nodes.len() + self.context.primitives.arity(op) > self.max_nodes() {
+            return self.mutate_constant(rng);
+        }
+        let mut wrapper: Vec<ArenaNode<T>> = vec![ArenaNode::new(op)];
+        if self.context.primitives.arity(op) == 2 && rng.gen() {
+            wrapper.push(ArenaNode::new(self.context.random_terminal(rng)));
+            let subtree = copy(&self.nodes, choice, None, &mut wrapper);
+            wrapper[0].children = [Some(1), Some(subtree)];
+        } else {
+            wrapper[0].children[0] = Some(copy(&self.nodes, choice, None, &mut wrapper));
+            if self.context.primitives.arity(op) == 2 {
+                wrapper[0].children[1] = Some(wrapper.len());
+                wrapper.push(ArenaNode::new(self.context.random_terminal(rng)));
+            }
+        }
+        self.graft(choice, &wrapper, 0);
+        Ok(())
+    }
+
+    /// Swap the operands of a random binary operation node
+    pub fn mutate_permutation<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        let ops: Vec<usize> = self.get_op_idxs().into_iter()
+            .filter(|&idx| self.context.primitives.arity(self.nodes[idx].node) == 2)
+            .collect();
+        if ops.is_empty() {
+            return self.mutate_constant(rng);
+        }
+        let choice = ops[rng.gen_range(0, ops.len())];
+        self.nodes[choice].children.swap(0, 1);
+        self.rebuild(None);
+        Ok(())
+    }
+
+    /// Swaps a random branch below the root of this tree with one of other, at any depth
+    /// Only pairs keeping both trees within max_nodes are considered
+    pub fn cross_over<R: Rng>(&mut self, other: &mut Self, rng: &mut R) -> Result<(), HeapError> {
+        if self.nodes.len() < 2 || other.nodes.len() < 2 {
+            return Err(HeapError::NoCandidates);
+        }
+        let (sizes, other_sizes) = (self.sizes(), other.sizes());
+        let choice = rng.gen_range(1, self.nodes.len());
+        let candidates: Vec<usize> = (1..other.nodes.len()).filter(|&other_choice| {
+            self.nodes.len() - sizes[choice] + other_sizes[other_choice] <= self.max_nodes() &&
+                other.nodes.len() - other_sizes[other_choice] + sizes[choice] <= other.max_nodes()
+        }).collect();
+        if candidates.is_empty() {
+            return Err(HeapError::NoCandidates);
+        }
+        let other_choice = candidates[rng.gen_range(0, candidates.len())];
+        let nodes = self.nodes.clone();
+        self.graft(choice, &other.nodes, other_choice);
+        other.graft(other_choice, &nodes, choice);
+        Ok(())
+    }
+
+    /// Checks that the nodes form a single tree in preorder, that operators have exactly
+    /// their operands with unary ones in the first slot, and that nodes belong to the context
+    /// Unlike SymbolicBinaryHeap::validate, trees deeper than max_depth are allowed
+    pub fn validate(&self) -> Result<(), HeapError> {
+        if self.nodes.is_empty() {
+            return Err(HeapError::Empty);
+        }
+        let mut visited: usize = 0;
+        let mut stack: Vec<usize> = vec![0];
+        while let Some(idx) = stack.pop() {
+            if idx != visited { // Out of range, shared, or out of preorder
+                return Err(HeapError::BadIndex(idx));
+            }
+            visited += 1;
+            let arena_node = self.nodes[idx];
+            match arena_node.node {
+                Node::Number(_) => (),
+                Node::Variable(i) => if i >= self.context.variables {
+                    return Err(HeapError::VariableOutOfRange(idx));
+                },
+                op => if self.context.primitives.get(op).is_none() {
+                    return Err(HeapError::UnknownOperator(idx));
+                }
+            };
+            let arity = self.context.primitives.arity(arena_node.node);
+            for (side, child) in arena_node.children.iter().enumerate() {
+                match *child {
+                    Some(child) if side >= arity => return Err(HeapError::Orphan(child)),
+                    None if side < arity => return Err(HeapError::MissingOperand(idx)),
+                    _ => ()
+                }
+            }
+            stack.extend(arena_node.children.iter().rev().flatten());
+        }
+        if visited < self.nodes.len() { // Unreachable nodes
+            return Err(HeapError::Orphan(visited));
+        }
+        Ok(())
+    }
+
+    /// Evaluates the subtree at idx from its last node back, as operands follow their
+    /// operator in preorder, so trees of any depth are evaluated without recursing
+    fn _collapse(&self, idx: usize, variables: &[T]) -> T {
+        let mut values: Vec<T> = vec![T::ZERO; self.nodes.len() - idx];
+        for position in (idx..self.nodes.len()).rev() {
+            let arena_node = self.nodes[position];
+            let operand = |side: usize| match arena_node.children[side] {
+                Some(child) => T::MIN_POSITIVE + values[child - idx],
+                None => T::MIN_POSITIVE
+            };
+            values[position - idx] = match arena_node.node {
+                Node::Number(n) => n,
+                Node::Variable(i) => variables[i],
+                op => self.context.primitives.apply(op, operand(0), operand(1))
+            };
+        }
+        values[0]
+    }
+
+    /// Evaluates the tree for the input variables, padding operands as SymbolicBinaryHeap does
+    pub fn collapse(&self, variables: &[T]) -> T {
+        if self.nodes.is_empty() {T::ZERO} else {self._collapse(0, variables)}
+    }
+}
+
+/// Prints the expression in infix notation, as SymbolicBinaryHeap does
+impl<T: Float> fmt::Display for ArenaTree<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let node_at = |idx: Option<usize>| idx.and_then(|idx| self.nodes.get(idx)).map(|n| {
+            let [left, right] = n.children;
+            (n.node, left, right)
+        });
+        write!(f, "{}", infix(&self.context, &node_at, Some(0)).0)
+    }
+}
+
+impl<T: Float> ExpressionTree<T> for ArenaTree<T> {
+    fn new(context: &Rc<Context<T>>) -> Self {
+        ArenaTree::new(context)
+    }
+
+    fn from_expr(expr: &Expr<T>, context: &Rc<Context<T>>) -> Result<Self, String> {
+        Ok(ArenaTree::from_expr(expr, context))
+    }
+
+    fn context(&self) -> &Rc<Context<T>> {
+        &self.context
+    }
+
+    fn spawn<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        ArenaTree::spawn(self, rng)
+    }
+
+    fn random_full<R: Rng>(&mut self, depth: u32, rng: &mut R) -> Result<(), HeapError> {
+        ArenaTree::random_full(self, depth, rng)
+    }
+
+    fn random_grow<R: Rng>(&mut self, depth: u32, rng: &mut R) -> Result<(), HeapError> {
+        ArenaTree::random_grow(self, depth, rng)
+    }
+
+    fn has_variable(&self) -> bool {
+        ArenaTree::has_variable(self)
+    }
+
+    fn ensure_variable<R: Rng>(&mut self, rng: &mut R) -> bool {
+        ArenaTree::ensure_variable(self, rng)
+    }
+
+    fn mutate_constant<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        ArenaTree::mutate_constant(self, rng)
+    }
+
+    fn mutate_clip<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        ArenaTree::mutate_clip(self, rng)
+    }
+
+    fn mutate_swap<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        ArenaTree::mutate_swap(self, rng)
+    }
+
+    fn mutate_similar<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        ArenaTree::mutate_similar(self, rng)
+    }
+
+    fn mutate_hoist<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        ArenaTree::mutate_hoist(self, rng)
+    }
+
+    fn mutate_shrink<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        ArenaTree::mutate_shrink(self, rng)
+    }
+
+    fn mutate_insert<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        ArenaTree::mutate_insert(self, rng)
+    }
+
+    fn mutate_permutation<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        ArenaTree::mutate_permutation(self, rng)
+    }
+
+    fn mutate_terminal<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        ArenaTree::mutate_terminal(self, rng)
+    }
+
+    fn cross_over<R: Rng>(&mut self, other: &mut Self, rng: &mut R) -> Result<(), HeapError> {
+        ArenaTree::cross_over(self, other, rng)
+    }
+
+    fn collapse(&self, variables: &[T]) -> T {
+        ArenaTree::collapse(self, variables)
+    }
+
+    fn compile(&self) -> Program<T> {
+        ArenaTree::compile(self)
+    }
+
+    fn optimize_constants(&mut self, points: &[Point<T>], iterations: usize) -> usize {
+        ArenaTree::optimize_constants(self, points, iterations)
+    }
+
+    fn simplify(&mut self) {
+        ArenaTree::simplify(self)
+    }
+
+    fn structural_hash(&self) -> u64 {
+        ArenaTree::structural_hash(self)
+    }
+
+    fn complexity(&self) -> u32 {
+        ArenaTree::complexity(self)
+    }
+
+    fn depth(&self) -> u32 {
+        ArenaTree::depth(self)
+    }
+
+    fn repair<R: Rng>(&mut self, rng: &mut R) -> Result<bool, HeapError> {
+        ArenaTree::repair(self, rng)
+    }
+
+    fn validate(&self) -> Result<(), HeapError> {
+        ArenaTree::validate(self)
+    }
+
+    fn to_expr(&self) -> Expr<T> {
+        ArenaTree::to_expr(self)
+    }
+
+    fn slots(&self) -> usize {
+        self.nodes.capacity()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate rand;
+
+    use self::rand::SeedableRng;
+    use self::rand::rngs::StdRng;
+    use super::*;
+    use super::super::PrimitiveSet;
+
+    /// Unwraps the result of an operation, which may find nothing to do on a tree that
+    /// reached max_nodes with no constant left
+    fn unless_full(result: Result<(), HeapError>) {
+        if let Err(err) = result {
+            assert_eq!(err, HeapError::NoCandidates);
+        }
+    }
+
+    #[test]
+    fn grows_past_max_depth_and_stays_valid() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut context: Context<f64> = Context::new(2, PrimitiveSet::all());
+        context.max_depth = 4;
+        let context = Rc::new(context);
+        let (mut tree, mut other) = (ArenaTree::new(&context), ArenaTree::new(&context));
+        tree.spawn(&mut rng).unwrap();
+        other.spawn(&mut rng).unwrap();
+        for i in 0..200 {
+            unless_full(tree.mutate_constant(&mut rng));
+            unless_full(tree.mutate_swap(&mut rng));
+            unless_full(other.mutate_constant(&mut rng));
+            unless_full(other.mutate_insert(&mut rng));
+            unless_full(match i % 6 {
+                0 => tree.mutate_clip(&mut rng),
+                1 => tree.mutate_similar(&mut rng),
+                2 => tree.mutate_hoist(&mut rng),
+                3 => tree.mutate_shrink(&mut rng),
+                4 => tree.mutate_permutation(&mut rng),
+                _ => tree.mutate_terminal(&mut rng)
+            });
+            unless_full(tree.cross_over(&mut other, &mut rng));
+            tree.validate().unwrap();
+            other.validate().unwrap();
+            assert!(tree.nodes.len().max(other.nodes.len()) <= tree.max_nodes());
+        }
+        assert!(tree.depth().max(other.depth()) > context.max_depth);
+    }
+
+    #[test]
+    fn walks_deep_trees_without_recursing() {
+        let context: Rc<Context<f64>> = Rc::new(Context::new(1, PrimitiveSet::all()));
+        let depth = 1_000_000;
+        let mut tree = ArenaTree::new(&context);
+        tree.nodes = (1..depth).map(|child| {
+            ArenaNode {node: Node::Abs, children: [Some(child), None]}
+        }).collect();
+        tree.nodes.push(ArenaNode::new(Node::Variable(0)));
+        tree.validate().unwrap();
+        tree.rebuild(None);
+        assert_eq!(tree.nodes.len(), depth);
+        assert_eq!(tree.collapse(&[3.0]), 3.0);
+        assert_eq!(tree.compile().evaluate(&[3.0]), 3.0);
+    }
+}
@@ -0,0 +1,176 @@
+use std::cmp::Ordering;
+
+use super::{ArenaTree, Expr, Float, Node, SymbolicBinaryHeap};
+
+/// Significant digits kept of constants in canonical form
+/// Constants agreeing to this many digits are the same formula, such as 1.93 and 1.9300001
+pub const CANONICAL_DIGITS: usize = 6;
+
+/// Operators whose operands can be swapped
+fn is_commutative<T>(op: Node<T>) -> bool {
+    matches!(op, Node::Add | Node::Multiply | Node::Min | Node::Max)
+}
+
+/// Rounds to a number of significant digits, also turning -0 into 0
+fn round_constant<T: Float>(n: T, digits: usize) -> T {
+    if n == T::ZERO {
+        return T::ZERO;
+    }
+    if !n.is_finite() {
+        return n;
+    }
+    format!("{:.*e}", digits.saturating_sub(1), n.to_f64()).parse::<f64>()
+        .map_or(n, T::from_f64)
+}
+
+/// Ranks terminals and operators: variables, then operators, then numbers
+fn rank<T>(node: &Node<T>) -> (u8, usize) {
+    match node {
+        Node::Variable(i) => (0, *i),
+        Node::Number(_) => (2, 0),
+        op => (1, op.op_index().unwrap_or(0))
+    }
+}
+
+/// A total order on expressions, used to sort commutative operands
+fn compare<T: Float>(a: &Expr<T>, b: &Expr<T>) -> Ordering {
+    let order = rank(&a.node()).cmp(&rank(&b.node()));
+    if order != Ordering::Equal {
+        return order;
+    }
+    match (a, b) {
+        (Expr::Terminal(Node::Number(m)), Expr::Terminal(Node::Number(n))) => {
+            m.partial_cmp(n).unwrap_or(Ordering::Equal) },
+        (Expr::Unary(_, x), Expr::Unary(_, y)) => compare(x, y),
+        (Expr::Binary(_, l1, r1), Expr::Binary(_, l2, r2)) => {
+            compare(l1, l2).then_with(|| compare(r1, r2)) },
+        _ => Ordering::Equal
+    }
+}
+
+/// 64-bit FNV-1a, which unlike the standard hasher is the same on every platform and release
+struct Fnv(u64);
+
+impl Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.write(&n.to_le_bytes());
+    }
+}
+
+impl<T: Float> Expr<T> {
+    /// Returns the canonical form: constants rounded to CANONICAL_DIGITS significant digits
+    /// and operands of add, mul, min and max sorted, so that x + 2 and 2 + x are identical
+    pub fn canonical(self) -> Self {
+        match self {
+            Expr::Terminal(Node::Number(n)) => Expr::number(round_constant(n, CANONICAL_DIGITS)),
+            Expr::Terminal(_) => self,
+            Expr::Unary(op, operand) => Expr::unary(op, operand.canonical()),
+            Expr::Binary(op, left, right) => {
+                let (left, right) = (left.canonical(), right.canonical());
+                if is_commutative(op) && compare(&left, &right) == Ordering::Greater {
+                    Expr::binary(op, right, left)
+                } else {
+                    Expr::binary(op, left, right)
+                } }
+        }
+    }
+
+    fn hash_into(&self, hasher: &mut Fnv) {
+        let node = self.node();
+        match node {
+            Node::Number(n) => {
+                hasher.write(b"n");
+                hasher.write_u64(n.to_f64().to_bits()); },
+            Node::Variable(i) => {
+                hasher.write(b"v");
+                hasher.write_u64(i as u64); },
+            op => {
+                hasher.write(b"o");
+                hasher.write_u64(op.op_index().unwrap_or(0) as u64); }
+        }
+        match self {
+            Expr::Terminal(_) => (),
+            Expr::Unary(_, operand) => operand.hash_into(hasher),
+            Expr::Binary(_, left, right) => {
+                left.hash_into(hasher);
+                right.hash_into(hasher); }
+        }
+    }
+}
+
+impl<T: Float> SymbolicBinaryHeap<T> {
+    /// Rewrites the heap in canonical form, see Expr::canonical
+    /// Unused slots are cleared, so equal formulas get equal heaps
+    pub fn canonicalize(&mut self) {
+        let expr = self.to_expr().canonical();
+        // Reordering operands never deepens an expression, so it always fits
+        self.heap = SymbolicBinaryHeap::from_expr(&expr, &self.context).unwrap().heap;
+    }
+
+    /// A hash of the canonical form, equal for the same formula at any heap layout
+    /// Stable across runs and platforms, for duplicate detection and fitness caching
+    pub fn structural_hash(&self) -> u64 {
+        let mut hasher = Fnv(0xcbf2_9ce4_8422_2325);
+        self.to_expr().canonical().hash_into(&mut hasher);
+        hasher.0
+    }
+}
+
+impl<T: Float> ArenaTree<T> {
+    /// A hash of the canonical form, equal to that of SymbolicBinaryHeap for the same formula
+    pub fn structural_hash(&self) -> u64 {
+        let mut hasher = Fnv(0xcbf2_9ce4_8422_2325);
+        self.to_expr().canonical().hash_into(&mut hasher);
+        hasher.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use super::*;
+    use super::super::{Context, PrimitiveSet};
+
+    fn context() -> Rc<Context<f64>> {
+        Rc::new(Context::new(1, PrimitiveSet::all()))
+    }
+
+    fn hash(s: &str) -> u64 {
+        SymbolicBinaryHeap::parse(s, &context()).unwrap().structural_hash()
+    }
+
+    #[test]
+    fn commuted_operands_hash_the_same() {
+        let same = [("x + 2", "2 + x"), ("x * sin(x) + 1", "1 + sin(x) * x"),
+                    ("2 * min(x, 3)", "min(3, x) * 2"), ("x + 1.93", "1.9300001 + x")];
+        for &(a, b) in &same {
+            assert_eq!(hash(a), hash(b), "{} and {}", a, b);
+        }
+        let different = [("x - 2", "2 - x"), ("x / 2", "2 / x"), ("x + 2", "x + 3")];
+        for &(a, b) in &different {
+            assert_ne!(hash(a), hash(b), "{} and {}", a, b);
+        }
+    }
+
+    #[test]
+    fn unused_slots_dont_matter() {
+        let context = context();
+        let clean = SymbolicBinaryHeap::parse("2 + x", &context).unwrap();
+        let mut slots = clean.heap.clone();
+        slots[3] = Some(Node::Number(5.0)); // Left below the terminals, as moved branches can
+        slots[6] = Some(Node::Variable(0));
+        let mut stray = SymbolicBinaryHeap::new_from(slots, &context).unwrap();
+        assert_eq!(stray.structural_hash(), clean.structural_hash());
+        let mut commuted = SymbolicBinaryHeap::parse("x + 2", &context).unwrap();
+        stray.canonicalize();
+        commuted.canonicalize();
+        assert_eq!(stray.heap, commuted.heap);
+    }
+}
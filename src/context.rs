@@ -0,0 +1,241 @@
+extern crate rand;
+
+use std::cell::RefCell;
+use std::f64::consts::{E, PI};
+use self::rand::Rng;
+use self::rand::distributions::Normal;
+
+use super::{Float, Node, PrimitiveSet};
+#[cfg(feature = "jit")]
+use super::JitCache;
+
+/// Maximum tree depth when none is specified
+pub const DEFAULT_MAX_DEPTH: u32 = 6;
+/// Range constants are drawn and kept in when none is specified
+pub const DEFAULT_CONSTANT_RANGE: (f64, f64) = (-15.0, 15.0);
+/// Standard deviation of jitter_constant's noise, relative to the constant's magnitude
+const JITTER: f64 = 0.1;
+/// Largest amount perturb_constant adds, relative to the width of the constant range
+const PERTURB_STEP: f64 = 0.05;
+
+/// Distribution random constants are drawn from, within the context's constant range
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConstantSampling {
+    /// Uniformly over the middle half of the range, leaving mutation room to move
+    /// constants outwards, so [-7.5, 7.5] by default
+    Uniform,
+    /// Normally with this mean and standard deviation, clamped to the range
+    Normal {mean: f64, std_dev: f64},
+    /// Uniformly over the logarithms of a range excluding 0, so every order of magnitude
+    /// is as likely, constants share the sign of the range
+    LogUniform,
+    /// Uniformly from a fixed set of values
+    Pool(Vec<f64>)
+}
+
+impl ConstantSampling {
+    /// Parses uniform, normal followed by mean and standard deviation, log_uniform, or pool
+    /// followed by its values, where pi and e stand for themselves
+    pub fn from_names(names: &[&str]) -> Result<Self, String> {
+        let number = |name: &str| match name {
+            "pi" => Ok(PI),
+            "e" => Ok(E),
+            _ => name.parse::<f64>().map_err(|err| format!("{:?} isn't a number, {}", name, err))
+        };
+        match names.split_first() {
+            Some((&"uniform", [])) => Ok(ConstantSampling::Uniform),
+            Some((&"log_uniform", [])) => Ok(ConstantSampling::LogUniform),
+            Some((&"normal", [mean, std_dev])) => {
+                Ok(ConstantSampling::Normal {mean: number(mean)?, std_dev: number(std_dev)?}) },
+            Some((&"pool", values)) if !values.is_empty() => {
+                Ok(ConstantSampling::Pool(values.iter().map(|value| number(value))
+                                                .collect::<Result<_, _>>()?)) },
+            _ => Err(format!("{:?} must be uniform, log_uniform, normal followed by mean and \
+                              std_dev, or pool followed by values", names))
+        }
+    }
+
+    /// Checks the sampling can draw constants in range, a (min, max) pair
+    pub fn check(&self, range: (f64, f64)) -> Result<(), String> {
+        let (min, max) = range;
+        if !(min.is_finite() && max.is_finite() && min < max) {
+            return Err(format!("constant range {:?} must be finite with min < max", range));
+        }
+        match *self {
+            ConstantSampling::Uniform => Ok(()),
+            ConstantSampling::Normal {mean, std_dev} => {
+                if mean.is_finite() && std_dev.is_finite() && std_dev > 0.0 {Ok(())} else {
+                    Err(format!("normal sampling needs a finite mean and a positive standard \
+                                 deviation, not {} and {}", mean, std_dev))
+                } },
+            ConstantSampling::LogUniform => {
+                if min > 0.0 || max < 0.0 {Ok(())} else {
+                    Err(format!("log_uniform sampling needs a range excluding 0, not {:?}",
+                                range))
+                } },
+            ConstantSampling::Pool(ref values) => {
+                match values.iter().find(|&&value| !(min <= value && value <= max)) {
+                    Some(value) => Err(format!("pool value {} is outside the constant range {:?}",
+                                               value, range)),
+                    None => Ok(())
+                } }
+        }
+    }
+}
+
+/// Settings shared by every heap of a simulation
+#[derive(Debug, Clone)]
+pub struct Context<T> {
+    /// Number of input features, Node::Variable indices are below this
+    pub variables: usize,
+    pub primitives: PrimitiveSet<T>,
+    /// Deepest level a node may occupy, with the root at depth 1
+    /// Heaps hold 2^max_depth slots, so this should stay modest
+    pub max_depth: u32,
+    /// Evaluate fitness through native code for expressions evaluated often enough to repay
+    /// compiling them, see JitFunction, needs the jit feature
+    pub jit: bool,
+    /// Multiplies the squared error of expressions unbounded over the training domain,
+    /// see SymbolicBinaryHeap::evaluate_interval, 1 to skip the check or infinity to reject
+    /// Custom operators without a range are given the benefit of the doubt
+    pub unbounded_penalty: f64,
+    /// Score each expression f by its best fit a + b * f, with a and b solved in closed form,
+    /// so the search needn't find offsets and scales, see Individual::model
+    pub linear_scaling: bool,
+    /// Bounds of random constants, mutated constants rebound off them, see ConstantSampling::check
+    /// Constant optimization and simplification may still leave them
+    pub constant_range: (f64, f64),
+    pub constant_sampling: ConstantSampling,
+    /// Column buffers lent to batch evaluation, kept to be reused across calls
+    pub(crate) buffers: RefCell<Vec<Vec<T>>>,
+    #[cfg(feature = "jit")]
+    pub(crate) jit_cache: JitCache<T>
+}
+
+impl<T: Float> Context<T> {
+    pub fn new(variables: usize, primitives: PrimitiveSet<T>) -> Self {
+        Context {variables, primitives, max_depth: DEFAULT_MAX_DEPTH, jit: false,
+                 unbounded_penalty: 1.0, linear_scaling: false,
+                 constant_range: DEFAULT_CONSTANT_RANGE,
+                 constant_sampling: ConstantSampling::Uniform,
+                 buffers: RefCell::new(Vec::new()),
+                 #[cfg(feature = "jit")]
+                 jit_cache: JitCache::default()}
+    }
+
+    /// Returns a random enabled operator
+    pub fn random_op<R: Rng>(&self, rng: &mut R) -> Node<T> {
+        let ops = self.primitives.enabled_nodes();
+        ops[rng.gen_range(0, ops.len())]
+    }
+
+    /// Returns a random enabled operator taking arity operands, if there is one
+    pub fn random_op_with_arity<R: Rng>(&self, arity: usize, rng: &mut R) -> Option<Node<T>> {
+        let ops = self.primitives.enabled_with_arity(arity);
+        if ops.is_empty() {
+            return None;
+        }
+        Some(ops[rng.gen_range(0, ops.len())])
+    }
+
+    /// Returns a random terminal, a variable or a constant with equal odds
+    pub fn random_terminal<R: Rng>(&self, rng: &mut R) -> Node<T> {
+        match rng.gen_range(0, 2) {
+            0 => Node::Variable(rng.gen_range(0, self.variables)),
+            _ => Node::Number(self.random_constant(rng))
+        }
+    }
+
+    /// Returns a constant drawn as constant_sampling says, within constant_range
+    pub fn random_constant<R: Rng>(&self, rng: &mut R) -> T {
+        let (min, max) = self.constant_range;
+        let value = match self.constant_sampling {
+            ConstantSampling::Uniform => {
+                let quarter = (max - min) / 4.0;
+                rng.gen_range(min + quarter, max - quarter) },
+            ConstantSampling::Normal {mean, std_dev} => rng.sample(Normal::new(mean, std_dev)),
+            ConstantSampling::LogUniform => {
+                let sign = if max < 0.0 {-1.0} else {1.0};
+                let (low, high) = if max < 0.0 {(-max, -min)} else {(min, max)};
+                sign * rng.gen_range(low.ln(), high.ln()).exp() },
+            ConstantSampling::Pool(ref values) => values[rng.gen_range(0, values.len())]
+        };
+        T::from_f64(value.max(min).min(max))
+    }
+
+    /// Clamps a computed constant to constant_range, or draws a random one if it isn't finite,
+    /// so that constants always print as numbers the parser reads back
+    pub fn bound_constant<R: Rng>(&self, n: T, rng: &mut R) -> T {
+        if !n.is_finite() {
+            return self.random_constant(rng);
+        }
+        let (min, max) = self.constant_range;
+        T::from_f64(n.to_f64().max(min).min(max))
+    }
+
+    /// Adds up to PERTURB_STEP of the constant range's width either way to a constant, or
+    /// multiplies it by a factor within (-1.5, 1.5), so steps scale with the range or constant
+    /// Results outside the constant range "rebound" with greater error, then get clamped
+    pub fn perturb_constant<R: Rng>(&self, n: T, rng: &mut R) -> T {
+        let (min, max) = self.constant_range;
+        let step = PERTURB_STEP * (max - min);
+        let do_add: bool = rng.gen();
+        let mut num = if do_add {
+            n + T::from_f64(rng.gen_range(-step, step))
+        } else {
+            n * T::from_f64(rng.gen_range(-1.5, 1.5))
+        };
+        let (min, max) = (T::from_f64(min), T::from_f64(max));
+        if num > max {
+            num -= T::from_f64(1.2) * (num - max);
+        } else if num < min {
+            num -= T::from_f64(1.2) * (num - min);
+        }
+        num.clamp(min, max)
+    }
+
+    /// Adds Gaussian noise with a standard deviation of JITTER times the constant's magnitude,
+    /// or JITTER for 0, clamped to constant_range
+    pub fn jitter_constant<R: Rng>(&self, n: T, rng: &mut R) -> T {
+        let n = n.to_f64();
+        let scale = if n != 0.0 {n.abs()} else {1.0};
+        let noise = rng.sample(Normal::new(0.0, JITTER * scale));
+        let (min, max) = self.constant_range;
+        T::from_f64((n + noise).max(min).min(max))
+    }
+
+    /// Name of a variable as printed and parsed: x, or x0, x1, ... with several inputs
+    pub fn variable_name(&self, i: usize) -> String {
+        if self.variables == 1 {"x".to_string()} else {format!("x{}", i)}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate rand;
+
+    use self::rand::SeedableRng;
+    use self::rand::rngs::StdRng;
+    use super::*;
+
+    #[test]
+    fn uniform_constants_fill_the_middle_half_of_the_range() {
+        let context = Context::<f64>::new(1, PrimitiveSet::default());
+        let mut rng = StdRng::seed_from_u64(1);
+        let constants: Vec<f64> = (0..1000).map(|_| context.random_constant(&mut rng)).collect();
+        assert!(constants.iter().all(|n| (-7.5..=7.5).contains(n)));
+        assert!(constants.iter().any(|&n| n < -7.0) && constants.iter().any(|&n| n > 7.0));
+    }
+
+    #[test]
+    fn perturbation_steps_scale_with_the_range() {
+        let mut context = Context::<f64>::new(1, PrimitiveSet::default());
+        context.constant_range = (-1e-3, 1e-3);
+        let mut rng = StdRng::seed_from_u64(1);
+        // Fixed steps of up to 1.5 would mostly overshoot and be clamped to the bounds
+        for _ in 0..1000 {
+            let n = context.perturb_constant(5e-4, &mut rng);
+            assert!(n.abs() < 7.5e-4, "{}", n);
+        }
+    }
+}
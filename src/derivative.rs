@@ -0,0 +1,149 @@
+use super::{Expr, Float, Node, PrimitiveSet, SymbolicBinaryHeap};
+
+fn add<T: Float>(l: Expr<T>, r: Expr<T>) -> Expr<T> {
+    Expr::binary(Node::Add, l, r)
+}
+
+fn sub<T: Float>(l: Expr<T>, r: Expr<T>) -> Expr<T> {
+    Expr::binary(Node::Subtract, l, r)
+}
+
+fn mul<T: Float>(l: Expr<T>, r: Expr<T>) -> Expr<T> {
+    Expr::binary(Node::Multiply, l, r)
+}
+
+fn div<T: Float>(l: Expr<T>, r: Expr<T>) -> Expr<T> {
+    Expr::binary(Node::Divide, l, r)
+}
+
+fn number<T: Float>(n: f64) -> Expr<T> {
+    Expr::number(T::from_f64(n))
+}
+
+impl<T: Float> Expr<T> {
+    /// Returns the unsimplified derivative with respect to variable var by the chain rule
+    /// Operators are differentiated as evaluated, so sqrt and pow act on |x| and log is ln|x|,
+    /// abs has slope sign(x) and min and max follow the operand they return
+    /// Fails on custom operators, which have no known derivative
+    pub fn derivative(&self, var: usize, primitives: &PrimitiveSet<T>)
+                                                        -> Result<Expr<T>, String> {
+        let (op, u, v) = match self {
+            Expr::Terminal(Node::Variable(i)) if *i == var => return Ok(number(1.0)),
+            Expr::Terminal(_) => return Ok(number(0.0)),
+            Expr::Unary(op, operand) => (*op, (**operand).clone(), None),
+            Expr::Binary(op, left, right) => (*op, (**left).clone(), Some((**right).clone()))
+        };
+        let du = u.derivative(var, primitives)?;
+        let dv = match v {
+            Some(ref v) => v.derivative(var, primitives)?,
+            None => number(0.0)
+        };
+        let v = v.unwrap_or_else(|| number(0.0));
+        Ok(match op {
+            Node::Add => add(du, dv),
+            Node::Subtract => sub(du, dv),
+            Node::Multiply => add(mul(du, v), mul(u, dv)),
+            Node::Divide if dv == number(0.0) => div(du, v),
+            Node::Divide => div(sub(mul(du, v.clone()), mul(u, dv)), mul(v.clone(), v)),
+            Node::Sine => mul(Expr::unary(Node::Cosine, u), du),
+            Node::Cosine => mul(Expr::unary(Node::Negate, Expr::unary(Node::Sine, u)), du),
+            Node::Exp => mul(Expr::unary(Node::Exp, u), du),
+            Node::Log => div(du, u),
+            // d/dx sqrt|u| = u u' / (2 |u| sqrt|u|)
+            Node::Sqrt => div(mul(u.clone(), du),
+                              mul(number(2.0), mul(Expr::unary(Node::Abs, u.clone()),
+                                                   Expr::unary(Node::Sqrt, u)))),
+            // d/dx |u|^v = |u|^v (v' ln|u| + v u' / u)
+            Node::Pow => mul(Expr::binary(Node::Pow, u.clone(), v.clone()),
+                             add(mul(dv, Expr::unary(Node::Log, u.clone())),
+                                 div(mul(v, du), u))),
+            // Padding makes sign(u) 1 at u = 0, the slope constant tuning gives abs there
+            Node::Abs => mul(Expr::unary(Node::Sign, u), du),
+            Node::Tanh => {
+                let tanh = Expr::unary(Node::Tanh, u);
+                mul(sub(number(1.0), mul(tanh.clone(), tanh)), du) },
+            Node::Square => mul(mul(number(2.0), u), du),
+            Node::Negate => Expr::unary(Node::Negate, du),
+            Node::Sign => number(0.0),
+            // The derivative of the operand returned, the left one on ties as T::min and
+            // T::max compare: v' + (u' - v') step(v - u) for min, with step(d) =
+            // (1 + sign(d)) / 2 being 1 at d = 0 once padded
+            Node::Min | Node::Max => {
+                let difference = if op == Node::Min {sub(v, u)} else {sub(u, v)};
+                let step = div(add(number(1.0), Expr::unary(Node::Sign, difference)),
+                               number(2.0));
+                add(dv.clone(), mul(sub(du, dv), step)) },
+            _ => {
+                let name = primitives.get(op).map_or("?", |primitive| &primitive.name);
+                return Err(format!("No derivative is known for the custom operator {}", name)); }
+        })
+    }
+}
+
+impl<T: Float> SymbolicBinaryHeap<T> {
+    /// Returns the simplified derivative with respect to variable var, in the same context
+    /// Fails if var is out of range, on custom operators, or if the result exceeds max_depth
+    pub fn derivative(&self, var: usize) -> Result<SymbolicBinaryHeap<T>, String> {
+        if var >= self.context.variables {
+            return Err(format!("Variable x{} is out of range for {} input variable(s)",
+                               var, self.context.variables));
+        }
+        let primitives = &self.context.primitives;
+        let expr = self.to_expr().derivative(var, primitives)?.simplify(primitives);
+        SymbolicBinaryHeap::from_expr(&expr, &self.context)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use super::*;
+    use super::super::Context;
+
+    fn context() -> Rc<Context<f64>> {
+        let mut context = Context::new(2, PrimitiveSet::all());
+        context.max_depth = 12;
+        Rc::new(context)
+    }
+
+    #[test]
+    fn matches_finite_differences() {
+        let context = context();
+        let expressions = ["abs(x0 * x1 - 1)", "max(x0, sin(x1))", "min(x0 * x0, x1) * x0",
+                           "sqrt(x0) + log(x1)", "x0 ^ x1", "tanh(x0 / x1)",
+                           "exp(cos(x0)) - square(x1)", "-abs(x0) * sign(x1)"];
+        let points = [[-1.3, 0.7], [0.4, -2.1], [1.9, 1.2]];
+        let h = 1e-6;
+        for s in &expressions {
+            let heap = SymbolicBinaryHeap::parse(s, &context).unwrap();
+            for var in 0..2 {
+                let derivative = heap.derivative(var).unwrap();
+                for point in &points {
+                    let (mut above, mut below) = (*point, *point);
+                    above[var] += h;
+                    below[var] -= h;
+                    let expected = (heap.collapse(&above) - heap.collapse(&below)) / (2.0 * h);
+                    let actual = derivative.collapse(point);
+                    assert!((actual - expected).abs() < 1e-6 * expected.abs().max(1.0),
+                            "d{} / dx{} at {:?} is {}, not {}", s, var, point, actual, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn degenerate_operands_follow_the_evaluator() {
+        let context = context();
+        // Ties pick the left operand, as constant tuning does
+        let cases = [("abs(x0 - x0)", 0.0, 0.0), ("max(x0, x0)", 0.0, 1.0),
+                     ("min(x1, x1) + x0", 0.0, 1.0), ("abs(x0)", 0.0, 1.0),
+                     ("max(x0, x0 * x0)", 1.0, 1.0), ("min(x0 * x0, x0)", 1.0, 2.0)];
+        for &(s, x, expected) in &cases {
+            let heap = SymbolicBinaryHeap::parse(s, &context).unwrap();
+            let derivative = heap.derivative(0).unwrap();
+            assert!((derivative.collapse(&[x, 0.0]) - expected).abs() < 1e-12,
+                    "d{} / dx0 at {} is {}", s, x, derivative);
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use std::error::Error;
+use std::fmt;
+
+/// Why a heap is invalid, or why an operation on it failed
+/// Indices are positions in SymbolicBinaryHeap::heap or ArenaTree::nodes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeapError {
+    /// The root slot is empty
+    Empty,
+    /// The operator at this index lacks an operand
+    MissingOperand(usize),
+    /// The node at this index hangs below a terminal, an empty slot,
+    /// or on the right of a unary operator
+    Orphan(usize),
+    /// The node at this index is deeper than max_depth
+    TooDeep(usize),
+    /// The variable at this index is beyond the context's inputs
+    VariableOutOfRange(usize),
+    /// The operator at this index isn't in the context's primitive set
+    UnknownOperator(usize),
+    /// An operation was given an index without a suitable node
+    BadIndex(usize),
+    /// An operation was asked to build a subtree that doesn't fit at this index
+    BadDepth {idx: usize, depth: u32},
+    /// An operation found no node to act on
+    NoCandidates
+}
+
+impl fmt::Display for HeapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeapError::Empty => write!(f, "The heap is empty"),
+            HeapError::MissingOperand(idx) => write!(f, "Operator at {} lacks an operand", idx),
+            HeapError::Orphan(idx) => write!(f, "Node at {} has no operator above it", idx),
+            HeapError::TooDeep(idx) => write!(f, "Node at {} is deeper than max_depth", idx),
+            HeapError::VariableOutOfRange(idx) => {
+                write!(f, "Variable at {} is out of range", idx) },
+            HeapError::UnknownOperator(idx) => write!(f, "Operator at {} is unknown", idx),
+            HeapError::BadIndex(idx) => write!(f, "No suitable node at {}", idx),
+            HeapError::BadDepth {idx, depth} => {
+                write!(f, "A subtree of depth {} doesn't fit at {}", depth, idx) },
+            HeapError::NoCandidates => write!(f, "No node to act on")
+        }
+    }
+}
+
+impl Error for HeapError {}
@@ -0,0 +1,129 @@
+use std::fmt;
+use std::rc::Rc;
+
+use super::{Context, Float, Node, SymbolicBinaryHeap};
+use sbh::{infix, length_from_depth};
+
+/// A boxed expression tree
+/// Used to build and transform expressions without heap index arithmetic
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr<T> {
+    Terminal(Node<T>),
+    Unary(Node<T>, Box<Expr<T>>),
+    Binary(Node<T>, Box<Expr<T>>, Box<Expr<T>>)
+}
+
+impl<T: Float> Expr<T> {
+    pub fn number(n: T) -> Self {
+        Expr::Terminal(Node::Number(n))
+    }
+
+    pub fn variable(i: usize) -> Self {
+        Expr::Terminal(Node::Variable(i))
+    }
+
+    pub fn unary(op: Node<T>, operand: Expr<T>) -> Self {
+        Expr::Unary(op, Box::new(operand))
+    }
+
+    pub fn binary(op: Node<T>, left: Expr<T>, right: Expr<T>) -> Self {
+        Expr::Binary(op, Box::new(left), Box::new(right))
+    }
+
+    /// The node at the root of this expression
+    pub fn node(&self) -> Node<T> {
+        match self {
+            Expr::Terminal(node) | Expr::Unary(node, _) | Expr::Binary(node, _, _) => *node
+        }
+    }
+
+    /// Number of levels, a single terminal has depth 1
+    pub fn depth(&self) -> u32 {
+        match self {
+            Expr::Terminal(_) => 1,
+            Expr::Unary(_, operand) => 1 + operand.depth(),
+            Expr::Binary(_, left, right) => 1 + left.depth().max(right.depth())
+        }
+    }
+
+    /// Largest variable index used, if any
+    pub fn max_variable(&self) -> Option<usize> {
+        match self {
+            Expr::Terminal(Node::Variable(i)) => Some(*i),
+            Expr::Terminal(_) => None,
+            Expr::Unary(_, operand) => operand.max_variable(),
+            Expr::Binary(_, left, right) => left.max_variable().max(right.max_variable())
+        }
+    }
+
+    /// Prints the expression in infix notation, naming variables and operators by context
+    pub fn display<'a>(&'a self, context: &'a Context<T>) -> impl fmt::Display + 'a {
+        ExprDisplay {expr: self, context}
+    }
+
+    /// Writes this expression into heap with its root at idx
+    fn write(&self, heap: &mut Vec<Option<Node<T>>>, idx: usize) {
+        heap[idx] = Some(self.node());
+        match self {
+            Expr::Terminal(_) => (),
+            Expr::Unary(_, operand) => operand.write(heap, 2 * idx + 1),
+            Expr::Binary(_, left, right) => {
+                left.write(heap, 2 * idx + 1);
+                right.write(heap, 2 * idx + 2); }
+        }
+    }
+}
+
+struct ExprDisplay<'a, T: 'a> {
+    expr: &'a Expr<T>,
+    context: &'a Context<T>
+}
+
+impl<'a, T: Float> fmt::Display for ExprDisplay<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let node_at = |expr: Option<&'a Expr<T>>| expr.map(|expr| match expr {
+            Expr::Terminal(node) => (*node, None, None),
+            Expr::Unary(node, operand) => (*node, Some(&**operand), None),
+            Expr::Binary(node, left, right) => (*node, Some(&**left), Some(&**right))
+        });
+        write!(f, "{}", infix(self.context, &node_at, Some(self.expr)).0)
+    }
+}
+
+impl<T: Float> SymbolicBinaryHeap<T> {
+    /// Builds a heap laid out from an expression tree
+    /// Fails if the expression is deeper than the context's max_depth
+    pub fn from_expr(expr: &Expr<T>, context: &Rc<Context<T>>) -> Result<Self, String> {
+        if expr.depth() > context.max_depth {
+            return Err(format!("Expression depth {} exceeds max_depth {}",
+                               expr.depth(), context.max_depth));
+        }
+        let mut heap: Vec<Option<Node<T>>> = vec![None; length_from_depth(context.max_depth) + 1];
+        expr.write(&mut heap, 0);
+        SymbolicBinaryHeap::new_from(heap, context).map_err(|err| err.to_string())
+    }
+
+    /// Returns the expression tree rooted at the top of the heap
+    /// Missing operands become the 0 they evaluate to
+    pub fn to_expr(&self) -> Expr<T> {
+        self.expr_at_idx(0)
+    }
+
+    fn expr_at_idx(&self, idx: usize) -> Expr<T> {
+        let node = match self.heap.get(idx) {
+            Some(Some(node)) => *node,
+            _ => return Expr::number(T::ZERO)
+        };
+        let (left_idx, right_idx) = (2 * idx + 1, 2 * idx + 2);
+        match self.context.primitives.arity(node) {
+            0 => Expr::Terminal(node),
+            1 => { // The operand is normally on the left
+                let operand_idx = match self.heap.get(left_idx) {
+                    Some(Some(_)) => left_idx,
+                    _ => right_idx
+                };
+                Expr::unary(node, self.expr_at_idx(operand_idx)) },
+            _ => Expr::binary(node, self.expr_at_idx(left_idx), self.expr_at_idx(right_idx))
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::fmt::{Debug, Display, LowerExp};
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign};
+use std::str::FromStr;
+
+/// Floating point types that expressions, points and fitness can be evaluated in
+/// Implemented for f32 and f64
+pub trait Float: Copy + Debug + Display + LowerExp + Default + PartialOrd + FromStr
+               + Sum + 'static + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self>
+               + Div<Output = Self> + Neg<Output = Self>
+               + AddAssign + SubAssign + MulAssign + DivAssign {
+    const ZERO: Self;
+    const ONE: Self;
+    const MIN_POSITIVE: Self;
+    const MAX: Self;
+    /// Difference between 1 and the next representable value
+    const EPSILON: Self;
+    const NAN: Self;
+
+    fn from_f64(value: f64) -> Self;
+    fn to_f64(self) -> f64;
+
+    fn sin(self) -> Self;
+    fn cos(self) -> Self;
+    fn exp(self) -> Self;
+    fn ln(self) -> Self;
+    fn sqrt(self) -> Self;
+    fn abs(self) -> Self;
+    fn tanh(self) -> Self;
+    fn powf(self, n: Self) -> Self;
+    fn powi(self, n: i32) -> Self;
+    fn min(self, other: Self) -> Self;
+    fn max(self, other: Self) -> Self;
+    fn clamp(self, min: Self, max: Self) -> Self;
+    fn is_finite(self) -> bool;
+    fn is_nan(self) -> bool;
+}
+
+macro_rules! impl_float {
+    ($t:ident) => {
+        impl Float for $t {
+            const ZERO: Self = 0.0;
+            const ONE: Self = 1.0;
+            const MIN_POSITIVE: Self = $t::MIN_POSITIVE;
+            const MAX: Self = $t::MAX;
+            const EPSILON: Self = $t::EPSILON;
+            const NAN: Self = $t::NAN;
+
+            fn from_f64(value: f64) -> Self { value as $t }
+            fn to_f64(self) -> f64 { self as f64 }
+
+            fn sin(self) -> Self { $t::sin(self) }
+            fn cos(self) -> Self { $t::cos(self) }
+            fn exp(self) -> Self { $t::exp(self) }
+            fn ln(self) -> Self { $t::ln(self) }
+            fn sqrt(self) -> Self { $t::sqrt(self) }
+            fn abs(self) -> Self { $t::abs(self) }
+            fn tanh(self) -> Self { $t::tanh(self) }
+            fn powf(self, n: Self) -> Self { $t::powf(self, n) }
+            fn powi(self, n: i32) -> Self { $t::powi(self, n) }
+            fn min(self, other: Self) -> Self { $t::min(self, other) }
+            fn max(self, other: Self) -> Self { $t::max(self, other) }
+            fn clamp(self, min: Self, max: Self) -> Self { $t::clamp(self, min, max) }
+            fn is_finite(self) -> bool { $t::is_finite(self) }
+            fn is_nan(self) -> bool { $t::is_nan(self) }
+        }
+    }
+}
+
+impl_float!(f32);
+impl_float!(f64);
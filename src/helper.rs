@@ -1,24 +1,87 @@
 extern crate rand;
 
-use self::rand::{thread_rng, Rng};
+use self::rand::Rng;
 use std::fmt::Debug;
 use std::process;
 use std::fs::File;
 use std::io::prelude::*;
 
+use super::{ConstantSampling, Float, Initialization, Mutation, MutationSelector, PrimitiveSet,
+            MUTATIONS, DEFAULT_CONSTANT_RANGE, DEFAULT_MAX_DEPTH};
+use primitives::DEFAULT_NAMES;
+
+/// Float type a simulation is evaluated in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Precision {
+    Single,
+    Double
+}
+
+/// Expression tree representation a simulation evolves, see ExpressionTree
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Representation {
+    Heap,
+    Arena
+}
+
+/// Simulation specifications read from the specs file
+#[derive(Debug, Clone)]
+pub struct Specs {
+    pub debug_level: usize,
+    pub skip: usize,
+    pub iterations: usize,
+    pub population_size: usize,
+    pub crossover_probability: f64,
+    pub mutation_probability: f64,
+    /// Names of the enabled operators, see PrimitiveSet::from_names
+    pub operators: Vec<String>,
+    pub max_depth: u32,
+    pub precision: Precision,
+    pub representation: Representation,
+    /// Simplify the population each this many iterations, never when 0
+    pub simplify_every: usize,
+    /// Levenberg–Marquardt steps tuning constants, none when 0
+    pub optimize_iterations: usize,
+    /// Optimize the constants of the optimize_top fittest each this many iterations, never when 0
+    pub optimize_every: usize,
+    pub optimize_top: usize,
+    /// How the initial population is grown
+    pub initialization: Initialization,
+    /// Give every initial individual a variable
+    pub require_variable: bool,
+    /// Infix expressions part of the initial population is copied from
+    pub seed_expressions: Vec<String>,
+    /// Share of the initial population copied from seed_expressions
+    pub seed_share: f64,
+    /// Relative odds of each mutation operator, indexed like Mutation::ALL
+    pub mutation_weights: [f64; MUTATIONS],
+    /// Shift the odds toward operators that improve fitness
+    pub adaptive_mutation: bool,
+    /// Evaluate fitness through JIT compiled native code
+    pub jit: bool,
+    /// Multiplies the squared error of expressions unbounded over the dataset's range
+    pub unbounded_penalty: f64,
+    /// Score expressions by their best fit a + b * f, see Context::linear_scaling
+    pub linear_scaling: bool,
+    /// Bounds of random constants, see Context::constant_range
+    pub constant_range: (f64, f64),
+    pub constant_sampling: ConstantSampling,
+    /// Seed of every random decision, drawn at random when None
+    pub seed: Option<u64>
+}
+
 pub fn print_vec<T: Debug>(v: &[T]) {
     for i in v.iter() { println!("{:?}", i); }   
 }
 
-pub fn select_index(cumulative_weights: &[f32]) -> usize {
+pub fn select_index<T: Float, R: Rng>(cumulative_weights: &[T], rng: &mut R) -> usize {
     // To do: Error Handling
     let last = cumulative_weights.last();
     //let max = cumulative_weights.iter().reduce(f32::max).max();
-    let w_sum = last.unwrap().min(f32::MAX);
-    let r: f32 = thread_rng().gen_range(0.0, w_sum);
-    cumulative_weights.iter().rposition(|&w| w < r).unwrap_or({
-        thread_rng().gen_range(0, cumulative_weights.len())
-    })
+    let w_sum = last.unwrap().min(T::MAX);
+    let r: T = T::from_f64(rng.gen_range(0.0, w_sum.to_f64()));
+    let fallback = rng.gen_range(0, cumulative_weights.len());
+    cumulative_weights.iter().rposition(|&w| w < r).unwrap_or(fallback)
 }
 
 pub fn read_file(filename: &String) -> String {
@@ -34,11 +97,15 @@ pub fn read_file(filename: &String) -> String {
     contents
 }
 
-pub fn parse_specs(contents: &str) -> Result<(usize, usize, usize, usize, f64, f64), String> { 
+/// The first line holds the six positional specs
+/// Each following line is an optional setting: a name, then its comma-separated values
+pub fn parse_specs(contents: &str) -> Result<Specs, String> { 
     // To do: Expected number of arguments + Expected type and range of arguments
-    let v: Vec<String> = contents.split(',')
-                               .map(|val| val.trim().to_string())
-                               .collect();
+    let mut lines = contents.lines().filter(|line| !line.trim().is_empty());
+    let v: Vec<String> = lines.next().unwrap_or("")
+                              .split(',')
+                              .map(|val| val.trim().to_string())
+                              .collect();
     if v.len() != 6 {
         return Err("Unexpected number of specs (must be exactly 6)".to_string());
     }
@@ -67,12 +134,183 @@ pub fn parse_specs(contents: &str) -> Result<(usize, usize, usize, usize, f64, f
         format!("mutation_probability = {} can't be parsed as a float.\nerror: {}\n", v[5], err)
     })?;
 
-    Ok((
+    let mut operators: Vec<String> = DEFAULT_NAMES.iter().map(|name| name.to_string()).collect();
+    let mut max_depth = DEFAULT_MAX_DEPTH;
+    let mut precision = Precision::Single;
+    let mut representation = Representation::Heap;
+    let mut simplify_every: usize = 0;
+    let mut optimize_iterations: usize = 50;
+    let mut optimize_every: usize = 0;
+    let mut optimize_top: usize = 1;
+    let mut initialization = Initialization::Random;
+    let mut require_variable = false;
+    let mut seed_expressions: Vec<String> = Vec::new();
+    let mut seed_share: f64 = 0.1;
+    let mut mutation_weights = [1.0; MUTATIONS];
+    let mut adaptive_mutation = false;
+    let mut jit = false;
+    let mut unbounded_penalty: f64 = 1.0;
+    let mut linear_scaling = false;
+    let mut constant_range = DEFAULT_CONSTANT_RANGE;
+    let mut constant_sampling = ConstantSampling::Uniform;
+    let mut seed: Option<u64> = None;
+    for line in lines {
+        let v: Vec<&str> = line.split(',').map(|val| val.trim()).collect();
+        match v[0] {
+            "operators" => {
+                PrimitiveSet::<f64>::from_names(&v[1..]).map_err(|err| {
+                    format!("operators = {} can't be parsed.\nerror: {}\n", line, err)
+                })?;
+                operators = v[1..].iter().map(|name| name.to_string()).collect(); },
+            "max_depth" => {
+                max_depth = v.get(1).unwrap_or(&"").parse().map_err(|err| {
+                    format!("max_depth = {} can't be parsed as integer.\nerror: {}\n", line, err)
+                })?;
+                if max_depth < 3 {
+                    return Err(format!("max_depth = {} must be at least 3", max_depth));
+                } },
+            "simplify_every" => {
+                simplify_every = v.get(1).unwrap_or(&"").parse().map_err(|err| {
+                    format!("simplify_every = {} can't be parsed as integer.\nerror: {}\n",
+                            line, err)
+                })?; },
+            "optimize_iterations" => {
+                optimize_iterations = v.get(1).unwrap_or(&"").parse().map_err(|err| {
+                    format!("optimize_iterations = {} can't be parsed as integer.\nerror: {}\n",
+                            line, err)
+                })?; },
+            "optimize_every" => {
+                optimize_every = v.get(1).unwrap_or(&"").parse().map_err(|err| {
+                    format!("optimize_every = {} can't be parsed as integer.\nerror: {}\n",
+                            line, err)
+                })?; },
+            "optimize_top" => {
+                optimize_top = v.get(1).unwrap_or(&"").parse().map_err(|err| {
+                    format!("optimize_top = {} can't be parsed as integer.\nerror: {}\n",
+                            line, err)
+                })?; },
+            "initialization" => {
+                initialization = match v.get(1) {
+                    Some(&"random") => Initialization::Random,
+                    Some(&"full") => Initialization::Full,
+                    Some(&"grow") => Initialization::Grow,
+                    Some(&"ramped_half_and_half") => Initialization::RampedHalfAndHalf,
+                    _ => return Err(format!("initialization = {} must be random, full, grow \
+                                             or ramped_half_and_half", line))
+                }; },
+            "require_variable" => {
+                require_variable = v.get(1).unwrap_or(&"").parse().map_err(|err| {
+                    format!("require_variable = {} can't be parsed as a boolean.\nerror: {}\n",
+                            line, err)
+                })?; },
+            "seed_expression" => {
+                // Everything after the name, as expressions such as min(x, 1) hold commas
+                match line.split_once(',').map(|(_, expr)| expr.trim()) {
+                    Some(expr) if !expr.is_empty() => seed_expressions.push(expr.to_string()),
+                    _ => return Err(format!("seed_expression = {} needs an expression", line))
+                }; },
+            "seed_share" => {
+                seed_share = v.get(1).unwrap_or(&"").parse().map_err(|err| {
+                    format!("seed_share = {} can't be parsed as a float.\nerror: {}\n",
+                            line, err)
+                })?;
+                if !(0.0..=1.0).contains(&seed_share) {
+                    return Err(format!("seed_share = {} must be between 0 and 1", seed_share));
+                } },
+            "mutation_weight" => {
+                let mutation = v.get(1).and_then(|name| Mutation::from_name(name)).ok_or_else(|| {
+                    format!("mutation_weight = {} must name constant, clip, swap, similar, hoist, \
+                             shrink, insert, permutation or terminal", line)
+                })?;
+                mutation_weights[mutation as usize] = v.get(2).unwrap_or(&"").parse()
+                    .map_err(|err| {
+                        format!("mutation_weight = {} can't be parsed as a float.\nerror: {}\n",
+                                line, err)
+                    })?; },
+            "adaptive_mutation" => {
+                adaptive_mutation = v.get(1).unwrap_or(&"").parse().map_err(|err| {
+                    format!("adaptive_mutation = {} can't be parsed as a boolean.\nerror: {}\n",
+                            line, err)
+                })?; },
+            "jit" => {
+                jit = v.get(1).unwrap_or(&"").parse().map_err(|err| {
+                    format!("jit = {} can't be parsed as a boolean.\nerror: {}\n", line, err)
+                })?;
+                if jit && !cfg!(feature = "jit") {
+                    return Err("jit = true needs a build with the jit feature".to_string());
+                } },
+            "unbounded_penalty" => {
+                unbounded_penalty = v.get(1).unwrap_or(&"").parse().map_err(|err| {
+                    format!("unbounded_penalty = {} can't be parsed as a float.\nerror: {}\n",
+                            line, err)
+                })?;
+                if unbounded_penalty.is_nan() || unbounded_penalty < 1.0 {
+                    return Err(format!("unbounded_penalty = {} must be at least 1",
+                                       unbounded_penalty));
+                } },
+            "linear_scaling" => {
+                linear_scaling = v.get(1).unwrap_or(&"").parse().map_err(|err| {
+                    format!("linear_scaling = {} can't be parsed as a boolean.\nerror: {}\n",
+                            line, err)
+                })?; },
+            "constant_range" => {
+                let bound = |value: Option<&&str>| value.unwrap_or(&"").parse().map_err(|err| {
+                    format!("constant_range = {} can't be parsed as two floats.\nerror: {}\n",
+                            line, err)
+                });
+                constant_range = (bound(v.get(1))?, bound(v.get(2))?); },
+            "constant_sampling" => {
+                constant_sampling = ConstantSampling::from_names(&v[1..]).map_err(|err| {
+                    format!("constant_sampling = {} can't be parsed.\nerror: {}\n", line, err)
+                })?; },
+            "seed" => {
+                seed = Some(v.get(1).unwrap_or(&"").parse().map_err(|err| {
+                    format!("seed = {} can't be parsed as integer.\nerror: {}\n", line, err)
+                })?); },
+            "precision" => {
+                precision = match v.get(1) {
+                    Some(&"f32") => Precision::Single,
+                    Some(&"f64") => Precision::Double,
+                    _ => return Err(format!("precision = {} must be f32 or f64", line))
+                }; },
+            "representation" => {
+                representation = match v.get(1) {
+                    Some(&"heap") => Representation::Heap,
+                    Some(&"arena") => Representation::Arena,
+                    _ => return Err(format!("representation = {} must be heap or arena", line))
+                }; },
+            _ => return Err(format!("Unknown setting {:?}", v[0]))
+        };
+    }
+    constant_sampling.check(constant_range)?;
+    MutationSelector::new(mutation_weights, adaptive_mutation)?;
+
+    Ok(Specs {
         debug_level,
         skip,
         iterations,
         population_size,
         crossover_probability,
-        mutation_probability
-    ))
+        mutation_probability,
+        operators,
+        max_depth,
+        precision,
+        representation,
+        simplify_every,
+        optimize_iterations,
+        optimize_every,
+        optimize_top,
+        initialization,
+        require_variable,
+        seed_expressions,
+        seed_share,
+        mutation_weights,
+        adaptive_mutation,
+        jit,
+        unbounded_penalty,
+        linear_scaling,
+        constant_range,
+        constant_sampling,
+        seed
+    })
 }
@@ -1,57 +1,47 @@
 extern crate rand;
 
-use std::mem::swap;
-use self::rand::{Rng, thread_rng};
+use std::fmt;
+use std::rc::Rc;
+use self::rand::Rng;
 
-use super::{Point, SymbolicBinaryHeap, MAX_IDX};
+use super::{Context, Expr, ExpressionTree, Float, HeapError, Interval, Mutation, Node, Point,
+            SymbolicBinaryHeap};
 
+/// A candidate expression and its fitness, its DNA in either representation
 #[derive(Debug, Clone)]
-pub struct Individual {
-    pub dna: SymbolicBinaryHeap<f32>,
-    pub fitness: f32,
+pub struct Individual<T, D = SymbolicBinaryHeap<T>> {
+    pub dna: D,
+    pub fitness: T,
+    /// Offset and slope the DNA's output was scaled by in fitness, see Context::linear_scaling
+    pub scaling: Option<(T, T)>,
     pub evaluations: usize
 }
 
-impl Individual {
-    pub fn new(points: &[Point]) -> Self {
-        let mut dna = SymbolicBinaryHeap::<f32>::new();
-        dna.spawn();
-        let fitness = fitness(&mut dna, &points);
+impl<T: Float, D: ExpressionTree<T>> Individual<T, D> {
+    /// Spawns a random individual, which fails if max_depth is below 3
+    pub fn new<R: Rng>(context: &Rc<Context<T>>, points: &[Point<T>], rng: &mut R)
+                                                      -> Result<Self, HeapError> {
+        let mut dna = D::new(context);
+        dna.spawn(rng)?;
+        let (fitness, scaling) = fitness(&dna, points);
         let evaluations: usize = 1;
-        Individual {dna, fitness, evaluations}
+        Ok(Individual {dna, fitness, scaling, evaluations})
     }
 
-    pub fn new_from(dna: SymbolicBinaryHeap<f32>, points: &[Point]) -> Self {
-        let mut dna = dna; 
-        let fitness = fitness(&mut dna, &points);
+    pub fn new_from(dna: D, points: &[Point<T>]) -> Self {
+        let (fitness, scaling) = fitness(&dna, points);
         let evaluations: usize = 1;
-        Individual {dna, fitness, evaluations}
+        Individual {dna, fitness, scaling, evaluations}
     }
     
     /// Choose a random target depth from each parent, and swap a random branch at that depth
     /// Of these four individuals, remove the worst performers or the most complicated
     /// Gendering of the individuals is done for clarity and dark humor
-    pub fn cross_over(mut self, father: &mut Individual, points: &[Point])
-                                               -> (Individual, Individual) {
-        let mut swap_idxs: Vec<(usize, usize)> = Vec::new();
-        swap_idxs.push((self.dna.get_swap_idx(), father.dna.get_swap_idx()));
-        self.dna.check_swap_idx(swap_idxs[0].0);
-        father.dna.check_swap_idx(swap_idxs[0].1);
-        // Swap values between the potential offspring
+    /// Fails if either parent has no branch to swap, or an offspring can't be made valid
+    pub fn cross_over<R: Rng>(mut self, father: &Self, points: &[Point<T>], rng: &mut R)
+                                                           -> Result<(Self, Self), HeapError> {
         let (mut daughter_dna, mut son_dna) = (self.dna.clone(), father.dna.clone());
-        while let Some((mom_idx, dad_idx)) = swap_idxs.pop() {
-            swap(&mut daughter_dna.heap[mom_idx], &mut son_dna.heap[dad_idx]);
-            let daughter_left_idx = 2 * mom_idx + 1;
-            let daughter_right_idx = 2 * mom_idx + 2;
-            let son_left_idx = 2 * dad_idx + 1;
-            let son_right_idx = 2 * dad_idx + 2;
-            if daughter_left_idx < MAX_IDX && son_left_idx < MAX_IDX {
-                swap_idxs.push((daughter_left_idx, son_left_idx));
-            }
-            if daughter_right_idx <= MAX_IDX && son_right_idx <= MAX_IDX {
-                swap_idxs.push((daughter_right_idx, son_right_idx));
-            }
-        }
+        daughter_dna.cross_over(&mut son_dna, rng)?;
         // Choose the two best individuals, carrying evalations into offspring
         let mut son = Individual::new_from(son_dna, points);
         if (father.fitness > son.fitness && father.dna.complexity() == son.dna.complexity())
@@ -61,47 +51,208 @@ impl Individual {
         }
         let daughter = Individual::new_from(daughter_dna, points);
         if daughter.fitness > self.fitness {
-            return (daughter, son);
+            Ok((daughter, son))
         } else {
             self.evaluations += 1;
-            return (self, son);
+            Ok((self, son))
         }
     }
 
-    /// Perform a random mutation from an array of possible actions
-    pub fn mutate(&mut self, points: &[Point]) {
-        let mut rng = thread_rng();
-        if self.dna.depth() > 2 {
-            match rng.gen_range(0, 4) {
-                0 => self.dna.mutate_constant(),
-                1 => self.dna.mutate_clip(),
-                2 => self.dna.mutate_swap(),
-                _ => self.dna.mutate_similar()
-            };
-        } else {
-            match rng.gen_range(0, 2) {
-                0 => self.dna.mutate_constant(),
-                _ => self.dna.mutate_similar()
-            };
+    /// Perform a mutation, see MutationSelector for choosing one
+    /// On failure the individual is left unchanged, so the mutation can be retried
+    pub fn mutate<R: Rng>(&mut self, mutation: Mutation, points: &[Point<T>], rng: &mut R)
+                                                                 -> Result<(), HeapError> {
+        let mut dna = self.dna.clone();
+        match mutation {
+            Mutation::Constant => dna.mutate_constant(rng),
+            Mutation::Clip => dna.mutate_clip(rng),
+            Mutation::Swap => dna.mutate_swap(rng),
+            Mutation::Similar => dna.mutate_similar(rng),
+            Mutation::Hoist => dna.mutate_hoist(rng),
+            Mutation::Shrink => dna.mutate_shrink(rng),
+            Mutation::Insert => dna.mutate_insert(rng),
+            Mutation::Permutation => dna.mutate_permutation(rng),
+            Mutation::Terminal => dna.mutate_terminal(rng)
+        }?;
+        dna.repair(rng)?;
+        dna.validate()?;
+        self.dna = dna;
+        self.update_fitness(points);
+        Ok(())
+    }
+
+    /// Tunes the DNA's constants against points, see ExpressionTree::optimize_constants
+    /// Kept only if fitness improves, returns whether it did
+    pub fn optimize_constants(&mut self, points: &[Point<T>], iterations: usize) -> bool {
+        let mut dna = self.dna.clone();
+        self.evaluations += dna.optimize_constants(points, iterations);
+        let (fitness, scaling) = fitness(&dna, points);
+        if fitness > self.fitness {
+            self.dna = dna;
+            self.fitness = fitness;
+            self.scaling = scaling;
+            return true;
         }
-        self.update_fitness(&points);
+        false
     }
 
-    pub fn update_fitness(&mut self, points: &[Point]) {
-        self.fitness = fitness(&mut self.dna, &points);
+    pub fn update_fitness(&mut self, points: &[Point<T>]) {
+        let (fitness, scaling) = fitness(&self.dna, points);
+        self.fitness = fitness;
+        self.scaling = scaling;
         self.evaluations += 1;
     }
+
+    /// The expression fitness was computed for: the DNA, scaled if linear scaling is on
+    /// May be deeper than max_depth
+    pub fn model(&self) -> Expr<T> {
+        let expr = self.dna.to_expr();
+        match self.scaling {
+            Some((a, b)) => Expr::binary(Node::Add, Expr::number(a),
+                                         Expr::binary(Node::Multiply, Expr::number(b), expr)),
+            None => expr
+        }
+    }
+
+    /// Predictions of the model at every point, matching those fitness scored
+    pub fn predict(&self, points: &[Point<T>]) -> Vec<T> {
+        let predictions = predict(&self.dna, points);
+        match self.scaling {
+            Some((a, b)) => predictions.into_iter().map(|p| a + b * p).collect(),
+            None => predictions
+        }
+    }
+
+    /// Simplifies the DNA unless that would leave a lone terminal, then updates fitness
+    pub fn simplify(&mut self, points: &[Point<T>]) {
+        let mut dna = self.dna.clone();
+        dna.simplify();
+        if dna.depth() > 1 {
+            self.dna = dna;
+            self.update_fitness(points);
+        }
+    }
+}
+
+/// Evaluates the DNA at every point, in batch or, if the context asks for it and the DNA
+/// has been evaluated often enough, through native code, see Context::jit
+fn predict<T: Float, D: ExpressionTree<T>>(dna: &D, points: &[Point<T>]) -> Vec<T> {
+    let program = dna.compile();
+    #[cfg(feature = "jit")]
+    {
+        let context = dna.context();
+        if context.jit {
+            if let Some(function) = context.jit_cache.function(&program, points.len()) {
+                return function.evaluate_points(points);
+            }
+        }
+    }
+    program.evaluate_points(points)
+}
+
+/// Offset a and slope b minimising the squared error of a + b * prediction, in closed form
+/// Predictions constant up to rounding get slope 0, rather than one blowing rounding noise up
+/// None is returned if any prediction isn't finite
+pub fn linear_scaling<T: Float>(predictions: &[T], points: &[Point<T>]) -> Option<(T, T)> {
+    if predictions.is_empty() || predictions.iter().any(|p| !p.is_finite()) {
+        return None;
+    }
+    let n = predictions.len() as f64;
+    let mean_prediction = predictions.iter().map(|p| p.to_f64()).sum::<f64>() / n;
+    let mean_y = points.iter().map(|point| point.y.to_f64()).sum::<f64>() / n;
+    let (mut covariance, mut variance) = (0.0, 0.0);
+    for (prediction, point) in predictions.iter().zip(points) {
+        let deviation = prediction.to_f64() - mean_prediction;
+        covariance += deviation * (point.y.to_f64() - mean_y);
+        variance += deviation * deviation;
+    }
+    let noise = T::EPSILON.to_f64() * mean_prediction.abs().max(1.0);
+    let b = if variance > n * noise * noise {covariance / variance} else {0.0};
+    let a = mean_y - b * mean_prediction;
+    if !a.is_finite() || !b.is_finite() {
+        return None;
+    }
+    Some((T::from_f64(a), T::from_f64(b)))
 }
 
 /// Sum of the squared error at each point
-fn fitness(dna: &mut SymbolicBinaryHeap<f32>, points: &[Point]) -> f32 {
-    let mut squared_error: f32 = f32::MIN_POSITIVE;
-    for point in points {
-        let difference = point.y - dna.collapse(point.x);
+/// Returns the fitness, along with the linear scaling applied if the context asks for it
+fn fitness<T: Float, D: ExpressionTree<T>>(dna: &D, points: &[Point<T>])
+                                                      -> (T, Option<(T, T)>) {
+    let mut squared_error: T = T::MIN_POSITIVE;
+    let mut predictions = predict(dna, points);
+    let scaling = if dna.context().linear_scaling {
+        linear_scaling(&predictions, points)
+    } else {None};
+    if let Some((a, b)) = scaling {
+        for prediction in predictions.iter_mut() {
+            *prediction = a + b * *prediction;
+        }
+    }
+    for (point, prediction) in points.iter().zip(predictions) {
+        let difference = point.y - prediction;
         squared_error +=  difference.powi(2);
     }
     if !dna.has_variable() { // Penalize constant functions
-        squared_error *= 10.0;
+        squared_error *= T::from_f64(10.0);
+    }
+    let penalty = dna.context().unbounded_penalty;
+    if penalty != 1.0 {
+        let report = dna.evaluate_interval(&Interval::domain(points));
+        if !report.bounded && !report.unknown {
+            squared_error *= T::from_f64(penalty);
+        }
+    }
+    (T::ONE / squared_error, scaling)
+}
+
+/// Prints the model in infix notation, scaling included
+impl<T: Float, D: ExpressionTree<T>> fmt::Display for Individual<T, D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.scaling.is_none() {
+            return write!(f, "{}", self.dna);
+        }
+        write!(f, "{}", self.model().display(self.dna.context()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::PrimitiveSet;
+
+    fn points(a: f64, b: f64) -> Vec<Point<f64>> {
+        (0..9).map(|i| {
+            let x = f64::from(i) - 4.0;
+            Point::new(vec![x], a + b * (x * x - x))
+        }).collect()
+    }
+
+    #[test]
+    fn recovers_offset_and_slope() {
+        let points = points(2.5, -4.0);
+        let predictions: Vec<f64> = points.iter().map(|p| p.x[0] * p.x[0] - p.x[0]).collect();
+        assert_eq!(linear_scaling(&predictions, &points), Some((2.5, -4.0)));
+        let flat = vec![7.0; points.len()];
+        let mean = points.iter().map(|point| point.y).sum::<f64>() / points.len() as f64;
+        assert_eq!(linear_scaling(&flat, &points), Some((mean, 0.0)));
+        let broken = vec![f64::NAN; points.len()];
+        assert_eq!(linear_scaling(&broken, &points), None);
+    }
+
+    #[test]
+    fn scaled_fitness_fits_exactly() {
+        let mut context = Context::new(1, PrimitiveSet::all());
+        context.linear_scaling = true;
+        let context = Rc::new(context);
+        let points = points(-1.0, 0.5);
+        let dna = SymbolicBinaryHeap::parse("x0 * x0 - x0", &context).unwrap();
+        let guy: Individual<f64> = Individual::new_from(dna, &points);
+        let (a, b) = guy.scaling.unwrap();
+        assert!((a + 1.0).abs() < 1e-12 && (b - 0.5).abs() < 1e-12, "{} {}", a, b);
+        for (prediction, point) in guy.predict(&points).iter().zip(&points) {
+            assert!((prediction - point.y).abs() < 1e-12);
+        }
+        assert!(guy.fitness > 1e20);
     }
-    1.0 / squared_error
 }
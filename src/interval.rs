@@ -0,0 +1,277 @@
+use std::f64::consts::PI;
+
+use primitives::sign;
+use super::{ArenaTree, Float, Instruction, Node, Point, Program, SymbolicBinaryHeap};
+
+/// A closed range of values, unbounded when either end is infinite
+/// Arithmetic doesn't round outwards, so bounds are as tight as the float type allows
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interval<T> {
+    pub lo: T,
+    pub hi: T
+}
+
+/// What interval evaluation found out about an expression over a domain
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IntervalReport<T> {
+    /// Bounds on every output over the domain
+    pub bounds: Interval<T>,
+    /// Whether both bounds are finite
+    pub bounded: bool,
+    /// Whether some divisor interval contains zero, where protected division hides a pole
+    pub divides_by_zero: bool,
+    /// Whether the bounds are only unbounded for want of a range on some custom operator,
+    /// see PrimitiveSet::set_range
+    pub unknown: bool
+}
+
+impl<T: Float> Interval<T> {
+    /// Orders its ends, any NaN making the interval unbounded
+    pub fn new(lo: T, hi: T) -> Self {
+        if lo.is_nan() || hi.is_nan() {
+            return Interval::unbounded();
+        }
+        Interval {lo: lo.min(hi), hi: lo.max(hi)}
+    }
+
+    pub fn point(n: T) -> Self {
+        Interval {lo: n, hi: n}
+    }
+
+    pub fn unbounded() -> Self {
+        let infinity = T::ONE / T::ZERO;
+        Interval {lo: -infinity, hi: infinity}
+    }
+
+    /// The range of each input variable over some points
+    pub fn domain(points: &[Point<T>]) -> Vec<Self> {
+        let dimensions = points.first().map_or(0, |point| point.dimensions());
+        (0..dimensions).map(|i| {
+            let first = Interval::point(points[0].x[i]);
+            points.iter().fold(first, |range, point| {
+                Interval {lo: range.lo.min(point.x[i]), hi: range.hi.max(point.x[i])}
+            })
+        }).collect()
+    }
+
+    pub fn is_bounded(&self) -> bool {
+        self.lo.is_finite() && self.hi.is_finite()
+    }
+
+    pub fn contains(&self, n: T) -> bool {
+        self.lo <= n && n <= self.hi
+    }
+
+    /// Smallest interval holding f of every corner
+    fn corners<F>(a: Self, b: Self, f: F) -> Self where F: Fn(T, T) -> T {
+        let values = [f(a.lo, b.lo), f(a.lo, b.hi), f(a.hi, b.lo), f(a.hi, b.hi)];
+        if values.iter().any(|value| value.is_nan()) {
+            return Interval::unbounded();
+        }
+        let lo = values.iter().fold(values[0], |lo, &value| lo.min(value));
+        let hi = values.iter().fold(values[0], |hi, &value| hi.max(value));
+        Interval {lo, hi}
+    }
+
+    /// Image under a non-decreasing function
+    fn increasing<F>(self, f: F) -> Self where F: Fn(T) -> T {
+        Interval::new(f(self.lo), f(self.hi))
+    }
+
+    fn add(self, other: Self) -> Self {
+        Interval::new(self.lo + other.lo, self.hi + other.hi)
+    }
+
+    fn abs(self) -> Self {
+        if self.contains(T::ZERO) {
+            Interval {lo: T::ZERO, hi: self.hi.max(-self.lo)}
+        } else {
+            Interval::new(self.lo.abs(), self.hi.abs())
+        }
+    }
+
+    fn square(self) -> Self {
+        let x = self.abs();
+        Interval::new(x.lo * x.lo, x.hi * x.hi)
+    }
+
+    /// Image under sin, with the shift in multiples of pi / 2 giving cos
+    fn sine(self, quarter_turns: f64) -> Self {
+        let (lo, hi) = (self.lo.to_f64() + quarter_turns * PI / 2.0,
+                        self.hi.to_f64() + quarter_turns * PI / 2.0);
+        if !lo.is_finite() || !hi.is_finite() || hi - lo >= 2.0 * PI {
+            return Interval::new(-T::ONE, T::ONE);
+        }
+        // Whether the range holds offset + 2 pi k for some integer k
+        let reaches = |offset: f64| {
+            ((lo - offset) / (2.0 * PI)).ceil() <= ((hi - offset) / (2.0 * PI)).floor()
+        };
+        let (a, b) = (lo.sin(), hi.sin());
+        let lo = if reaches(-PI / 2.0) {-1.0} else {a.min(b)};
+        let hi = if reaches(PI / 2.0) {1.0} else {a.max(b)};
+        Interval::new(T::from_f64(lo), T::from_f64(hi))
+    }
+
+    /// Applies an operator to padded operand intervals, as PrimitiveSet::apply does to values
+    /// Returns whether a divisor contains zero along with the result
+    fn apply(op: Node<T>, l: Self, r: Self) -> (Self, bool) {
+        let x = l.add(r); // The operand of unary operators
+        let result = match op {
+            Node::Add => l.add(r),
+            Node::Subtract => Interval::new(l.lo - r.hi, l.hi - r.lo),
+            Node::Multiply => Interval::corners(l, r, |a, b| a * b),
+            Node::Divide => {
+                if r.contains(T::ZERO) {
+                    let result = if l == Interval::point(T::ZERO) {l} else {Interval::unbounded()};
+                    return (result, true);
+                }
+                Interval::corners(l, r, |a, b| a / b) },
+            Node::Sine => x.sine(0.0),
+            Node::Cosine => x.sine(1.0),
+            Node::Exp => x.increasing(T::exp),
+            // ln|x| falls to minus infinity at 0, where protected_log returns 0
+            Node::Log => match x.abs() {
+                x if x.hi == T::ZERO => x,
+                x if x.lo == T::ZERO => {
+                    Interval::new(Interval::unbounded().lo, x.hi.ln().max(T::ZERO)) },
+                x => x.increasing(T::ln)
+            },
+            Node::Sqrt => x.abs().increasing(T::sqrt),
+            // |l|^r is monotone in each argument, so the corners bound it
+            Node::Pow => Interval::corners(l.abs(), r, T::powf),
+            Node::Abs => x.abs(),
+            Node::Tanh => x.increasing(T::tanh),
+            Node::Square => x.square(),
+            Node::Negate => Interval::new(-x.hi, -x.lo),
+            Node::Min => Interval::new(l.lo.min(r.lo), l.hi.min(r.hi)),
+            Node::Max => Interval::new(l.lo.max(r.lo), l.hi.max(r.hi)),
+            Node::Sign => x.increasing(sign),
+            // Nothing is known about custom operators without a range
+            _ => Interval::unbounded()
+        };
+        (result, false)
+    }
+}
+
+/// An interval on the evaluation stack
+struct Operand<T> {
+    interval: Interval<T>,
+    /// Position of the subtree's first instruction
+    start: usize,
+    /// Whether the interval depends on a custom operator without a range
+    unknown: bool,
+    /// Whether it is unbounded regardless of any custom operator without a range
+    unbounded: bool
+}
+
+impl<T: Float> SymbolicBinaryHeap<T> {
+    /// Evaluates the expression over a box of inputs, see Program::evaluate_interval
+    pub fn evaluate_interval(&self, domain: &[Interval<T>]) -> IntervalReport<T> {
+        self.compile().evaluate_interval(domain)
+    }
+}
+
+impl<T: Float> ArenaTree<T> {
+    /// Evaluates the expression over a box of inputs, see Program::evaluate_interval
+    pub fn evaluate_interval(&self, domain: &[Interval<T>]) -> IntervalReport<T> {
+        self.compile().evaluate_interval(domain)
+    }
+}
+
+impl<T: Float> Program<T> {
+    /// Evaluates the program over a box of inputs, one interval per variable
+    /// Useful to find poles that protected division hides between training points
+    /// Bounds are conservative: each use of a variable ranges independently, bar the product
+    /// of identical operands being a square, so x / (x * x + 1) is bounded but
+    /// x / (x * x - x + 1) over [-2, 3] is reported as dividing by zero
+    pub fn evaluate_interval(&self, domain: &[Interval<T>]) -> IntervalReport<T> {
+        let padding = Interval::point(T::MIN_POSITIVE);
+        let instructions = &self.instructions;
+        let mut divides_by_zero = false;
+        let mut stack: Vec<Operand<T>> = Vec::new();
+        for (position, &instruction) in instructions.iter().enumerate() {
+            let operand = match instruction {
+                Instruction::Number(n) => {
+                    Operand {interval: Interval::point(n), start: position, unknown: false,
+                             unbounded: false} },
+                Instruction::Variable(i) => {
+                    Operand {interval: domain[i], start: position, unknown: false,
+                             unbounded: false} },
+                Instruction::Operator(op, has_left, has_right) => {
+                    let right = if has_right {stack.pop()} else {None};
+                    let left = if has_left {stack.pop()} else {None};
+                    let pad = |operand: &Option<Operand<T>>| match operand {
+                        Some(operand) => padding.add(operand.interval),
+                        None => padding
+                    };
+                    let (l, r) = (pad(&left), pad(&right));
+                    let operands = || left.iter().chain(right.iter());
+                    let range = self.context.primitives.get(op).and_then(|p| p.range);
+                    let (interval, pole) = match (op, range, &left, &right) {
+                        (_, Some((lo, hi)), _, _) => {
+                            (Interval::new(T::from_f64(lo), T::from_f64(hi)), false) },
+                        (Node::Multiply, _, Some(left), Some(right))
+                                if instructions[left.start..right.start] ==
+                                   instructions[right.start..position] => {
+                            (l.square(), false) },
+                        _ => Interval::apply(op, l, r)
+                    };
+                    divides_by_zero |= pole;
+                    let unranged = matches!(op, Node::Custom(_)) && range.is_none();
+                    let unknown = unranged || operands().any(|operand| operand.unknown);
+                    let unbounded = !interval.is_bounded() && !unranged &&
+                        (!unknown || operands().any(|operand| operand.unbounded));
+                    let start = operands().next().map_or(position, |operand| operand.start);
+                    Operand {interval, start, unknown, unbounded} }
+            };
+            stack.push(operand);
+        }
+        let result = stack.pop().unwrap();
+        let bounded = result.interval.is_bounded();
+        IntervalReport {bounds: result.interval, bounded, divides_by_zero,
+                        unknown: !bounded && !result.unbounded}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use super::*;
+    use super::super::{Context, PrimitiveSet};
+
+    fn report(s: &str, context: &Rc<Context<f64>>) -> IntervalReport<f64> {
+        let heap = SymbolicBinaryHeap::parse(s, context).unwrap();
+        heap.evaluate_interval(&[Interval::new(-2.0, 3.0)])
+    }
+
+    #[test]
+    fn products_of_identical_operands_are_squares() {
+        let context = Rc::new(Context::new(1, PrimitiveSet::all()));
+        let square = report("x / (x * x + 1)", &context);
+        assert!(square.bounded && !square.divides_by_zero);
+        assert!(report("(x + 1) * (x + 1)", &context).bounds.lo >= 0.0);
+        assert!(report("x / (x * x - x + 1)", &context).divides_by_zero);
+    }
+
+    #[test]
+    fn custom_operators_are_unknown_without_a_range() {
+        let mut primitives = PrimitiveSet::all();
+        let softsign = primitives.register_unary("softsign", |x: f64| x / (1.0 + x.abs()))
+                                 .unwrap();
+        let context = Rc::new(Context::new(1, primitives.clone()));
+        let unknown = report("2 * softsign(x)", &context);
+        assert!(!unknown.bounded && unknown.unknown);
+        // The pole is unbounded whatever softsign gives
+        let pole = report("softsign(x) + 1 / (x - 0.5)", &context);
+        assert!(!pole.bounded && !pole.unknown);
+
+        primitives.set_range(softsign, (-1.0, 1.0)).unwrap();
+        let context = Rc::new(Context::new(1, primitives.clone()));
+        let ranged = report("2 * softsign(x)", &context);
+        assert!(ranged.bounded && !ranged.unknown);
+        assert_eq!(ranged.bounds, Interval::new(-2.0, 2.0));
+        assert!(primitives.set_range(Node::Sine, (-1.0, 1.0)).is_err());
+        assert!(primitives.set_range(softsign, (1.0, -1.0)).is_err());
+    }
+}
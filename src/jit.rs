@@ -0,0 +1,476 @@
+extern crate cranelift_codegen;
+extern crate cranelift_frontend;
+extern crate cranelift_jit;
+extern crate cranelift_module;
+extern crate cranelift_native;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::mem::{size_of, transmute};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::rc::Rc;
+
+use self::cranelift_codegen::ir::condcodes::FloatCC;
+use self::cranelift_codegen::ir::immediates::{Ieee32, Ieee64};
+use self::cranelift_codegen::ir::{types, AbiParam, InstBuilder, MemFlags, Type, Value};
+use self::cranelift_codegen::settings::{self, Configurable};
+use self::cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use self::cranelift_jit::{JITBuilder, JITModule};
+use self::cranelift_module::{default_libcall_names, Module};
+
+use primitives::{self, protected_log};
+use super::{Eval, Float, Instruction, Node, Point, Program, SymbolicBinaryHeap};
+
+/// Instructions times points an expression is evaluated over in batch before it's compiled,
+/// a few times what compiling costs, so only expressions evaluated again and again are
+const COMPILE_WORK: usize = 1 << 20;
+/// Expressions a JitCache keeps count of before it starts over
+const CACHE_SIZE: usize = 4096;
+
+/// Called back from native code for custom operators, evaluated as in the PrimitiveSet
+/// A panic can't unwind through native frames, so a panicking operator evaluates to NaN
+extern "C" fn apply_eval<T: Float>(eval: *const Eval<T>, l: T, r: T) -> T {
+    let eval = unsafe { &*eval };
+    catch_unwind(AssertUnwindSafe(|| match eval {
+        Eval::Unary(f) => f(l + r),
+        Eval::Binary(f) => f(l, r)
+    })).unwrap_or(T::NAN)
+}
+
+// Built-in operators without a native instruction, called directly, the same as in builtins
+extern "C" fn sine<T: Float>(x: T) -> T {x.sin()}
+extern "C" fn cosine<T: Float>(x: T) -> T {x.cos()}
+extern "C" fn exp<T: Float>(x: T) -> T {x.exp()}
+extern "C" fn log<T: Float>(x: T) -> T {protected_log(x)}
+extern "C" fn tanh<T: Float>(x: T) -> T {x.tanh()}
+extern "C" fn sign<T: Float>(x: T) -> T {primitives::sign(x)}
+extern "C" fn pow<T: Float>(l: T, r: T) -> T {l.abs().powf(r)}
+extern "C" fn min<T: Float>(l: T, r: T) -> T {l.min(r)}
+extern "C" fn max<T: Float>(l: T, r: T) -> T {l.max(r)}
+
+fn unary_builtin<T: Float>(op: Node<T>) -> extern "C" fn(T) -> T {
+    match op {
+        Node::Sine => sine,
+        Node::Cosine => cosine,
+        Node::Exp => exp,
+        Node::Log => log,
+        Node::Tanh => tanh,
+        Node::Sign => sign,
+        _ => unreachable!("{:?} isn't called as a unary built-in", op)
+    }
+}
+
+fn binary_builtin<T: Float>(op: Node<T>) -> extern "C" fn(T, T) -> T {
+    match op {
+        Node::Pow => pow,
+        Node::Min => min,
+        Node::Max => max,
+        _ => unreachable!("{:?} isn't called as a binary built-in", op)
+    }
+}
+
+/// Whether an operator compiles to native instructions rather than a call
+fn is_native<T: Float>(op: Node<T>) -> bool {
+    matches!(op, Node::Add | Node::Subtract | Node::Multiply | Node::Divide | Node::Sqrt |
+                 Node::Abs | Node::Square | Node::Negate)
+}
+
+/// An expression compiled to native machine code with Cranelift
+/// Gives the same results as SymbolicBinaryHeap::collapse, padding and protection included
+/// The native code loops over rows of variable values, writing one result per row
+pub struct JitFunction<T> {
+    module: Option<JITModule>,
+    function: extern "C" fn(*const T, usize, *mut T),
+    /// Number of variables the native code reads, the length of a row
+    variables: usize,
+    /// Evaluations of the custom operators, pointed to by the native code
+    _evals: Box<[Eval<T>]>
+}
+
+impl<T> Drop for JitFunction<T> {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // Nothing can call into the code after the function is dropped
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+impl<T: Float> SymbolicBinaryHeap<T> {
+    /// Compiles the expression to native code, see JitFunction
+    pub fn jit(&self) -> Result<JitFunction<T>, String> {
+        JitFunction::new(self)
+    }
+}
+
+/// A float constant of the native type
+fn constant<T: Float>(builder: &mut FunctionBuilder, float: Type, n: T) -> Value {
+    if float == types::F32 {
+        builder.ins().f32const(Ieee32::with_float(n.to_f64() as f32))
+    } else {
+        builder.ins().f64const(Ieee64::with_float(n.to_f64()))
+    }
+}
+
+impl<T: Float> JitFunction<T> {
+    pub fn new(heap: &SymbolicBinaryHeap<T>) -> Result<Self, String> {
+        JitFunction::from_program(&heap.compile())
+    }
+
+    /// Compiles a program, whose context supplies the custom operators
+    pub fn from_program(program: &Program<T>) -> Result<Self, String> {
+        let float = match size_of::<T>() {
+            4 => types::F32,
+            8 => types::F64,
+            size => return Err(format!("No native float type has {} bytes", size))
+        };
+        let variables = program.instructions.iter().filter_map(|instruction| match instruction {
+            Instruction::Variable(i) => Some(i + 1),
+            _ => None
+        }).max().unwrap_or(0);
+        let primitives = &program.context.primitives;
+        let evals: Box<[Eval<T>]> = program.instructions.iter().filter_map(|instruction| {
+            match *instruction {
+                Instruction::Operator(op @ Node::Custom(_), _, _) => {
+                    primitives.get(op).map(|primitive| primitive.eval.clone()) },
+                _ => None
+            }
+        }).collect();
+
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").map_err(|err| err.to_string())?;
+        let isa = cranelift_native::builder()
+            .map_err(|err| format!("Host machine is not supported: {}", err))?
+            .finish(settings::Flags::new(flags))
+            .map_err(|err| err.to_string())?;
+        let pointer = isa.pointer_type();
+        let mut module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+
+        // (rows, number of rows, results)
+        let mut signature = module.make_signature();
+        signature.params.push(AbiParam::new(pointer));
+        signature.params.push(AbiParam::new(pointer));
+        signature.params.push(AbiParam::new(pointer));
+        let mut unary = module.make_signature();
+        unary.params.push(AbiParam::new(float));
+        unary.returns.push(AbiParam::new(float));
+        let mut binary = unary.clone();
+        binary.params.push(AbiParam::new(float));
+        let mut callback = binary.clone();
+        callback.params.insert(0, AbiParam::new(pointer));
+
+        let id = module.declare_anonymous_function(&signature).map_err(|err| err.to_string())?;
+        let mut ctx = module.make_context();
+        ctx.func.signature = signature;
+        let mut builder_context = FunctionBuilderContext::new();
+        {
+            let mut builder = FunctionBuilder::new(&mut ctx.func, &mut builder_context);
+            let unary = builder.import_signature(unary);
+            let binary = builder.import_signature(binary);
+            let callback = builder.import_signature(callback);
+            let entry = builder.create_block();
+            let header = builder.create_block();
+            let body = builder.create_block();
+            let exit = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let (rows, count, results) = match *builder.block_params(entry) {
+                [rows, count, results] => (rows, count, results),
+                _ => unreachable!()
+            };
+            let zero = constant(&mut builder, float, T::ZERO);
+            let min_positive = constant(&mut builder, float, T::MIN_POSITIVE);
+            let apply = builder.ins().iconst(pointer, apply_eval::<T> as *const () as i64);
+            builder.ins().jump(header, &[rows, count, results]);
+
+            // Loops until no rows are left
+            let row = builder.append_block_param(header, pointer);
+            let left = builder.append_block_param(header, pointer);
+            let result = builder.append_block_param(header, pointer);
+            builder.switch_to_block(header);
+            builder.ins().brif(left, body, &[], exit, &[]);
+
+            builder.switch_to_block(body);
+            let mut stack: Vec<Value> = Vec::new();
+            let mut customs = evals.iter();
+            for instruction in &program.instructions {
+                let value = match *instruction {
+                    Instruction::Number(n) => constant(&mut builder, float, n),
+                    Instruction::Variable(i) => {
+                        let offset = (i * size_of::<T>()) as i32;
+                        builder.ins().load(float, MemFlags::trusted(), row, offset) },
+                    Instruction::Operator(op, has_left, has_right) => {
+                        // Children are padded as in _collapse
+                        let r = if has_right {
+                            let r = stack.pop().unwrap();
+                            builder.ins().fadd(min_positive, r)
+                        } else {min_positive};
+                        let l = if has_left {
+                            let l = stack.pop().unwrap();
+                            builder.ins().fadd(min_positive, l)
+                        } else {min_positive};
+                        match op {
+                            Node::Add => builder.ins().fadd(l, r),
+                            Node::Subtract => builder.ins().fsub(l, r),
+                            Node::Multiply => builder.ins().fmul(l, r),
+                            Node::Divide => { // Same as protected_divide
+                                let nonzero = builder.ins().fcmp(FloatCC::NotEqual, r, zero);
+                                let r = builder.ins().select(nonzero, r, min_positive);
+                                builder.ins().fdiv(l, r) },
+                            Node::Sqrt => {
+                                let x = builder.ins().fadd(l, r);
+                                let x = builder.ins().fabs(x);
+                                builder.ins().sqrt(x) },
+                            Node::Abs => {
+                                let x = builder.ins().fadd(l, r);
+                                builder.ins().fabs(x) },
+                            Node::Square => {
+                                let x = builder.ins().fadd(l, r);
+                                builder.ins().fmul(x, x) },
+                            Node::Negate => {
+                                let x = builder.ins().fadd(l, r);
+                                builder.ins().fneg(x) },
+                            Node::Sine | Node::Cosine | Node::Exp | Node::Log | Node::Tanh |
+                            Node::Sign => {
+                                let x = builder.ins().fadd(l, r);
+                                let f = unary_builtin(op) as *const () as i64;
+                                let f = builder.ins().iconst(pointer, f);
+                                let call = builder.ins().call_indirect(unary, f, &[x]);
+                                builder.inst_results(call)[0] },
+                            Node::Pow | Node::Min | Node::Max => {
+                                let f = binary_builtin(op) as *const () as i64;
+                                let f = builder.ins().iconst(pointer, f);
+                                let call = builder.ins().call_indirect(binary, f, &[l, r]);
+                                builder.inst_results(call)[0] },
+                            // Custom operators the PrimitiveSet doesn't know evaluate to 0,
+                            // as in apply
+                            Node::Custom(_) if primitives.get(op).is_some() => {
+                                let eval = customs.next().unwrap();
+                                let eval_ptr = builder.ins().iconst(pointer,
+                                                                    eval as *const _ as i64);
+                                let call = builder.ins().call_indirect(callback, apply,
+                                                                       &[eval_ptr, l, r]);
+                                builder.inst_results(call)[0] },
+                            _ => zero
+                        } }
+                };
+                stack.push(value);
+            }
+            let value = stack.pop().unwrap();
+            builder.ins().store(MemFlags::trusted(), value, result, 0);
+            let row = builder.ins().iadd_imm(row, (variables * size_of::<T>()) as i64);
+            let left = builder.ins().iadd_imm(left, -1);
+            let result = builder.ins().iadd_imm(result, size_of::<T>() as i64);
+            builder.ins().jump(header, &[row, left, result]);
+
+            builder.switch_to_block(exit);
+            builder.ins().return_(&[]);
+            builder.seal_all_blocks();
+            builder.finalize();
+        }
+        module.define_function(id, &mut ctx).map_err(|err| err.to_string())?;
+        module.clear_context(&mut ctx);
+        module.finalize_definitions().map_err(|err| err.to_string())?;
+        let code = module.get_finalized_function(id);
+        let function = unsafe {
+            transmute::<*const u8, extern "C" fn(*const T, usize, *mut T)>(code)
+        };
+        Ok(JitFunction {module: Some(module), function, variables, _evals: evals})
+    }
+
+    /// Runs the native loop over count rows of self.variables values each
+    fn run(&self, rows: &[T], count: usize) -> Vec<T> {
+        assert!(rows.len() >= count * self.variables);
+        let mut results: Vec<T> = vec![T::ZERO; count];
+        (self.function)(rows.as_ptr(), count, results.as_mut_ptr());
+        results
+    }
+
+    /// Evaluates at a single point, which needs a value for every variable used
+    pub fn evaluate(&self, variables: &[T]) -> T {
+        assert!(variables.len() >= self.variables,
+                "The expression reads {} variable(s) but {} were given",
+                self.variables, variables.len());
+        self.run(variables, 1)[0]
+    }
+
+    /// Evaluates over one column of values per input variable, of equal length
+    pub fn evaluate_batch(&self, columns: &[&[T]]) -> Vec<T> {
+        let length = columns.first().map_or(0, |column| column.len());
+        assert!(columns.iter().all(|column| column.len() == length),
+                "Input columns must have the same length");
+        assert!(columns.len() >= self.variables,
+                "The expression reads {} variable(s) but {} columns were given",
+                self.variables, columns.len());
+        let mut rows: Vec<T> = Vec::with_capacity(length * self.variables);
+        for j in 0..length {
+            rows.extend(columns[..self.variables].iter().map(|column| column[j]));
+        }
+        self.run(&rows, length)
+    }
+
+    /// Evaluates at every point, as used by fitness
+    pub fn evaluate_points(&self, points: &[Point<T>]) -> Vec<T> {
+        let mut rows: Vec<T> = Vec::with_capacity(points.len() * self.variables);
+        for point in points {
+            assert!(point.x.len() >= self.variables,
+                    "The expression reads {} variable(s) but a point has {}",
+                    self.variables, point.x.len());
+            rows.extend_from_slice(&point.x[..self.variables]);
+        }
+        self.run(&rows, points.len())
+    }
+}
+
+/// Compiled functions of the expressions a context evaluates the most, see Context::jit
+/// Compiling costs hundreds of batch evaluations of a small dataset, so an expression is
+/// only compiled once it has been evaluated over COMPILE_WORK operators times points, with
+/// calls counting against native instructions, as they run no faster than in batch
+pub(crate) struct JitCache<T> {
+    entries: RefCell<HashMap<Vec<u64>, CacheEntry<T>>>
+}
+
+struct CacheEntry<T> {
+    work: usize,
+    function: Option<Rc<JitFunction<T>>>
+}
+
+impl<T: Float> JitCache<T> {
+    /// The compiled program, once it has been evaluated often enough, at points more
+    /// Compilation failures leave the program to batch evaluation, which gives the same results
+    pub(crate) fn function(&self, program: &Program<T>, points: usize)
+                                                      -> Option<Rc<JitFunction<T>>> {
+        let mut entries = self.entries.borrow_mut();
+        let key = key(program);
+        if entries.len() >= CACHE_SIZE && !entries.contains_key(&key) {
+            entries.clear();
+        }
+        let entry = entries.entry(key).or_insert(CacheEntry {work: 0, function: None});
+        if entry.function.is_none() {
+            entry.work += points * savings(program);
+            if entry.work >= COMPILE_WORK {
+                entry.work = 0;
+                entry.function = JitFunction::from_program(program).ok().map(Rc::new);
+            }
+        }
+        entry.function.clone()
+    }
+}
+
+/// Identifies a program exactly, constants by their bits
+fn key<T: Float>(program: &Program<T>) -> Vec<u64> {
+    program.instructions.iter().flat_map(|instruction| match *instruction {
+        Instruction::Number(n) => [0, n.to_f64().to_bits()],
+        Instruction::Variable(i) => [1, i as u64],
+        Instruction::Operator(op, has_left, has_right) => {
+            [2 + has_left as u64 * 2 + has_right as u64 * 4, op.op_index().unwrap_or(0) as u64] }
+    }).collect()
+}
+
+/// Native instructions less calls per point, what compiling a program might save
+fn savings<T: Float>(program: &Program<T>) -> usize {
+    let (mut native, mut called) = (0usize, 0);
+    for instruction in &program.instructions {
+        match *instruction {
+            Instruction::Operator(op, _, _) if is_native(op) => native += 1,
+            Instruction::Operator(..) => called += 1,
+            _ => ()
+        }
+    }
+    native.saturating_sub(called)
+}
+
+impl<T> Default for JitCache<T> {
+    fn default() -> Self {
+        JitCache {entries: RefCell::new(HashMap::new())}
+    }
+}
+
+/// Clones start empty, compiled functions aren't shared
+impl<T> Clone for JitCache<T> {
+    fn clone(&self) -> Self {
+        JitCache::default()
+    }
+}
+
+impl<T> fmt::Debug for JitCache<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "JitCache({} expressions)", self.entries.borrow().len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate rand;
+
+    use self::rand::{Rng, SeedableRng};
+    use self::rand::rngs::StdRng;
+    use super::*;
+    use super::super::{Context, PrimitiveSet};
+
+    /// Compiles spawned heaps over every builtin and a custom operator, and checks native
+    /// evaluation against batch evaluation bit for bit, NaN included
+    fn matches_batch<T: Float>() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for &variables in &[1, 3] {
+            let mut primitives = PrimitiveSet::all();
+            primitives.register_binary("hypot", |l: T, r: T| (l * l + r * r).sqrt()).unwrap();
+            let context = Rc::new(Context::<T>::new(variables, primitives));
+            let points: Vec<Point<T>> = (0..20).map(|_| {
+                let x = (0..variables).map(|_| T::from_f64(rng.gen_range(-3.0, 3.0))).collect();
+                Point::new(x, T::ZERO)
+            }).collect();
+            for _ in 0..100 {
+                let mut heap = SymbolicBinaryHeap::new(&context);
+                heap.spawn(&mut rng).unwrap();
+                let function = heap.jit().unwrap();
+                let batch = heap.evaluate_points(&points);
+                let native = function.evaluate_points(&points);
+                for ((point, &expected), &value) in points.iter().zip(&batch).zip(&native) {
+                    for actual in &[value, function.evaluate(&point.x)] {
+                        assert!(actual.to_f64().to_bits() == expected.to_f64().to_bits() ||
+                                    (actual.is_nan() && expected.is_nan()),
+                                "{} at {:?}: {} but batch evaluation gives {}",
+                                heap, point.x, actual, expected);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn matches_batch_f32() {
+        matches_batch::<f32>();
+    }
+
+    #[test]
+    fn matches_batch_f64() {
+        matches_batch::<f64>();
+    }
+
+    #[test]
+    fn compiles_only_expressions_evaluated_often() {
+        let context = Rc::new(Context::<f64>::new(1, PrimitiveSet::all()));
+        let cache: JitCache<f64> = JitCache::default();
+        let program = SymbolicBinaryHeap::parse("x * x + 2 * x", &context).unwrap().compile();
+        let work = savings(&program) * 100;
+        let evaluations = (0..).position(|_| cache.function(&program, 100).is_some()).unwrap();
+        assert_eq!(evaluations, COMPILE_WORK.div_ceil(work) - 1);
+        // Calls run no faster natively, so an expression of little else is never compiled
+        let program = SymbolicBinaryHeap::parse("sin(x) + cos(x)", &context).unwrap().compile();
+        assert_eq!(savings(&program), 0);
+        assert!((0..100).all(|_| cache.function(&program, 100_000).is_none()));
+    }
+
+    #[test]
+    fn panicking_custom_operators_give_nan() {
+        let mut primitives = PrimitiveSet::all();
+        primitives.register_unary("fails", |x: f64| if x < 0.0 {panic!("negative")} else {x})
+            .unwrap();
+        let context = Rc::new(Context::new(1, primitives));
+        let function = SymbolicBinaryHeap::parse("fails(x) + 1", &context).unwrap().jit().unwrap();
+        assert!(function.evaluate(&[-1.0]).is_nan());
+        assert_eq!(function.evaluate(&[1.0]), 2.0);
+    }
+}
@@ -1,48 +1,111 @@
-use std::str::FromStr;
+extern crate rand;
+
+use std::rc::Rc;
+use rand::Rng;
 
 pub mod helper;
+mod float;
+mod error;
 mod sbh;
+mod expr;
+mod parser;
+mod simplify;
+mod derivative;
+mod program;
+mod interval;
+mod canonical;
+mod optimize;
+mod tree;
+mod arena;
+#[cfg(feature = "jit")]
+mod jit;
+mod primitives;
+mod context;
+mod mutation;
 mod individual;
 mod simulation;
 
-pub use sbh::{SymbolicBinaryHeap, Node, MAX_IDX};
+pub use float::Float;
+pub use error::HeapError;
+pub use sbh::{Initialization, SymbolicBinaryHeap, Node};
+pub use expr::Expr;
+pub use parser::parse_expr;
+pub use program::{Program, Instruction};
+pub use interval::{Interval, IntervalReport};
+pub use canonical::CANONICAL_DIGITS;
+pub use tree::ExpressionTree;
+pub use arena::{ArenaTree, ArenaNode};
+#[cfg(feature = "jit")]
+pub use jit::JitFunction;
+#[cfg(feature = "jit")]
+use jit::JitCache;
+pub use primitives::{PrimitiveSet, Primitive, Eval, BUILTIN_NAMES};
+pub use context::{ConstantSampling, Context, DEFAULT_CONSTANT_RANGE, DEFAULT_MAX_DEPTH};
+pub use mutation::{Mutation, MutationSelector, MUTATIONS};
 pub use individual::Individual;
 pub use simulation::Simulation;
 
+/// A single observation: the input features x and the target y
 #[derive(Debug, Clone)]
-pub struct Point {
-    pub x: f32,
-    pub y: f32,
+pub struct Point<T> {
+    pub x: Vec<T>,
+    pub y: T,
 }
 
-impl Point {
-    pub fn new(x: f32, y: f32) -> Self {
+impl<T: Float> Point<T> {
+    pub fn new(x: Vec<T>, y: T) -> Self {
         Point {x, y}
     }
+
+    /// Number of input features
+    pub fn dimensions(&self) -> usize {
+        self.x.len()
+    }
 }
 
-pub fn string_to_points(contents: &String) -> Vec<Point> {
-    // To do: Error handling: Unwrapping of line + expected # elements 
-    let mut points: Vec<Point> = Vec::new();
-    for line in contents.lines() {
-        let values: Vec<f32> = line.split(',')
-                                   .map(|val| f32::from_str(val.trim())
-                                   .unwrap())
-                                   .collect();
-        
-        let c = Point::new(values[0], values[1]);
+/// Reads one point per line, with any number of input columns
+/// The target column defaults to the last one, the remaining columns are inputs in order
+/// Fails on values that aren't numbers, a target beyond the columns, no input column
+/// or lines with differing numbers of columns
+pub fn string_to_points<T: Float>(contents: &str, target: Option<usize>)
+                                                        -> Result<Vec<Point<T>>, String> {
+    let mut points: Vec<Point<T>> = Vec::new();
+    for (i, line) in contents.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let mut values: Vec<T> = line.split(',')
+            .map(|val| val.trim().parse().map_err(|_| {
+                format!("{:?} on line {} can't be parsed as a float", val.trim(), i + 1)
+            }))
+            .collect::<Result<_, _>>()?;
+        let target = target.unwrap_or(values.len() - 1);
+        if target >= values.len() {
+            return Err(format!("target_column = {} is out of range for the {} column(s) \
+                                on line {}", target, values.len(), i + 1));
+        }
+        if values.len() < 2 {
+            return Err(format!("Line {} has no input column besides the target", i + 1));
+        }
+        let y = values.remove(target);
+        if let Some(first) = points.first().filter(|first| first.dimensions() != values.len()) {
+            return Err(format!("Line {} has {} input column(s) where earlier lines have {}",
+                               i + 1, values.len(), first.dimensions()));
+        }
+        let c = Point::new(values, y);
         points.push(c);
     }
-    points
+    if points.is_empty() {
+        return Err("The dataset has no points".to_string());
+    }
+    Ok(points)
 }
 
-pub fn select_parents(w: &[f32]) -> (usize, usize) {
-    let mom_index = helper::select_index(w);
-    let dad_index = helper::select_index(w);  
+pub fn select_parents<T: Float, R: Rng>(w: &[T], rng: &mut R) -> (usize, usize) {
+    let mom_index = helper::select_index(w, rng);
+    let dad_index = helper::select_index(w, rng);
     (mom_index, dad_index)
 }
 
-pub fn find_fittest(population: &[Individual]) -> Individual {
+pub fn find_fittest<T: Float, D: ExpressionTree<T>>(population: &[Individual<T, D>])
+                                                                      -> Individual<T, D> {
     let mut best_individual = &population[0];
     for individual in population {
         if best_individual.fitness < individual.fitness {
@@ -52,9 +115,9 @@ pub fn find_fittest(population: &[Individual]) -> Individual {
     best_individual.clone()
 }
 
-pub fn get_cumulative_weights(individuals: &[Individual]) -> Vec<f32> {
-    let mut running_sum = f32::MIN_POSITIVE;
-    let mut cumulative_weights: Vec<f32> = vec![0.0; individuals.len()];
+pub fn get_cumulative_weights<T: Float, D>(individuals: &[Individual<T, D>]) -> Vec<T> {
+    let mut running_sum = T::MIN_POSITIVE;
+    let mut cumulative_weights: Vec<T> = vec![T::ZERO; individuals.len()];
     for (i, individual) in individuals.iter().enumerate() {
         running_sum += individual.fitness;
         cumulative_weights[i] += running_sum;
@@ -62,11 +125,93 @@ pub fn get_cumulative_weights(individuals: &[Individual]) -> Vec<f32> {
     cumulative_weights
 }
 
-pub fn random_population(population_size: usize, points: &[Point]) -> Vec<Individual> {
-    let mut individuals: Vec<Individual> = Vec::new();
-    for _ in 0..population_size {
-        let indiv = Individual::new(points);
-        individuals.push(indiv);
+/// Grows population_size individuals as initialization says, fails if max_depth is below 3
+/// With require_variable, an individual without a variable gets one in place of a constant
+pub fn random_population<T, D, R>(population_size: usize, context: &Rc<Context<T>>,
+                                  points: &[Point<T>], initialization: Initialization,
+                                  require_variable: bool, rng: &mut R)
+                                                -> Result<Vec<Individual<T, D>>, HeapError>
+        where T: Float, D: ExpressionTree<T>, R: Rng {
+    let max_depth = context.max_depth;
+    if max_depth < 3 {
+        return Err(HeapError::BadDepth {idx: 0, depth: max_depth});
+    }
+    let mut individuals: Vec<Individual<T, D>> = Vec::new();
+    for i in 0..population_size {
+        let mut dna = D::new(context);
+        match initialization {
+            Initialization::Random => dna.spawn(rng)?,
+            Initialization::Full => dna.random_full(rng.gen_range(2, max_depth + 1), rng)?,
+            Initialization::Grow => dna.random_grow(rng.gen_range(2, max_depth + 1), rng)?,
+            Initialization::RampedHalfAndHalf => {
+                // Pairs share a depth, one full and one grown
+                let depth = 2 + (i / 2) as u32 % (max_depth - 1);
+                if i % 2 == 0 {dna.random_full(depth, rng)?} else {dna.random_grow(depth, rng)?} }
+        }
+        if require_variable {
+            dna.ensure_variable(rng);
+        }
+        individuals.push(Individual::new_from(dna, points));
     } 
-    individuals
+    Ok(individuals)
+}
+
+#[cfg(test)]
+mod test {
+    extern crate rand;
+
+    use self::rand::SeedableRng;
+    use self::rand::rngs::StdRng;
+
+    use super::*;
+
+    /// Level of every terminal, the root being at 1
+    fn leaf_levels<T: Float>(expr: &Expr<T>, level: u32, levels: &mut Vec<u32>) {
+        match expr {
+            Expr::Terminal(_) => levels.push(level),
+            Expr::Unary(_, operand) => leaf_levels(operand, level + 1, levels),
+            Expr::Binary(_, left, right) => {
+                leaf_levels(left, level + 1, levels);
+                leaf_levels(right, level + 1, levels); }
+        }
+    }
+
+    fn population<D: ExpressionTree<f64>>(initialization: Initialization)
+                                                           -> Vec<Individual<f64, D>> {
+        let mut context = Context::new(2, PrimitiveSet::all());
+        context.max_depth = 6;
+        let points = vec![Point::new(vec![0.5, -1.0], 1.0), Point::new(vec![2.0, 3.0], -2.0)];
+        let mut rng = StdRng::seed_from_u64(11);
+        random_population(40, &Rc::new(context), &points, initialization, true, &mut rng)
+            .unwrap()
+    }
+
+    fn check_shapes<D: ExpressionTree<f64>>() {
+        for guy in population::<D>(Initialization::Full) {
+            let mut levels = Vec::new();
+            leaf_levels(&guy.dna.to_expr(), 1, &mut levels);
+            assert!(levels.iter().all(|&level| level == levels[0]), "{}", guy.dna);
+            assert!(levels[0] >= 2 && levels[0] <= 6);
+            assert!(guy.dna.has_variable());
+        }
+        for guy in population::<D>(Initialization::Grow) {
+            assert!(guy.dna.depth() >= 2 && guy.dna.depth() <= 6, "{}", guy.dna);
+            assert!(guy.dna.validate().is_ok() && guy.dna.has_variable());
+        }
+        // Pairs share a depth, ramped from 2 to 6, one full and one grown
+        let ramped = population::<D>(Initialization::RampedHalfAndHalf);
+        for (i, pair) in ramped.chunks(2).enumerate() {
+            let depth = 2 + i as u32 % 5;
+            let mut levels = Vec::new();
+            leaf_levels(&pair[0].dna.to_expr(), 1, &mut levels);
+            assert!(levels.iter().all(|&level| level == depth), "{}", pair[0].dna);
+            assert!(pair[1].dna.depth() >= 2 && pair[1].dna.depth() <= depth);
+        }
+    }
+
+    #[test]
+    fn initialization_shapes() {
+        check_shapes::<SymbolicBinaryHeap<f64>>();
+        check_shapes::<ArenaTree<f64>>();
+    }
 }
@@ -3,39 +3,97 @@ extern crate symboreg;
 use std::process;
 use std::env;
 
-use symboreg::{Point, Simulation, helper, string_to_points};
+use symboreg::{ArenaTree, Context, Expr, ExpressionTree, Float, Point, PrimitiveSet, Simulation,
+               SymbolicBinaryHeap, helper, parse_expr, string_to_points};
+use symboreg::helper::{Precision, Representation, Specs};
 
 fn main() {
     // Parse arguments
     let mut args = env::args().skip(1);
     let specs_filename = args.next().unwrap_or_else( || {
         eprintln!("Please specify filename containing simulation specifications. \
-        \n USAGE: cargo run ./specs.csv /points.csv > output.csv");
+        \n USAGE: cargo run ./specs.csv /points.csv [target_column] > output.csv");
         process::exit(1); }
     );
     let points_filename = args.next().unwrap_or_else( || {
         eprintln!("Please specify filename containing dataset. \
-        \n USAGE: cargo run ./specs.csv /points.csv > output.csv");
+        \n USAGE: cargo run ./specs.csv /points.csv [target_column] > output.csv");
         process::exit(1); }
     );
+    // Optional index of the target column, defaults to the last column
+    let target: Option<usize> = args.next().map(|arg| arg.parse().unwrap_or_else(|err| {
+        eprintln!("target_column = {} can't be parsed as integer.\nerror: {}\n", arg, err);
+        process::exit(1); }
+    ));
     // Extract program specifications and dataset
     let contents = helper::read_file(&specs_filename);
-    let (debug_level, skip, iterations, population_size,
-         crossover_probability, mutation_probability)
-            = helper::parse_specs(&contents).unwrap_or_else(|err| {
+    let specs = helper::parse_specs(&contents).unwrap_or_else(|err| {
         eprintln!("{}", err);
         process::exit(1); }
     );
     let contents = helper::read_file(&points_filename);
-    let points: Vec<Point> = string_to_points(&contents);
+    match (specs.precision, specs.representation) {
+        (Precision::Single, Representation::Heap) => {
+            run::<f32, SymbolicBinaryHeap<f32>>(&specs, &contents, target) },
+        (Precision::Single, Representation::Arena) => {
+            run::<f32, ArenaTree<f32>>(&specs, &contents, target) },
+        (Precision::Double, Representation::Heap) => {
+            run::<f64, SymbolicBinaryHeap<f64>>(&specs, &contents, target) },
+        (Precision::Double, Representation::Arena) => {
+            run::<f64, ArenaTree<f64>>(&specs, &contents, target) }
+    };
+}
+
+fn run<T: Float, D: ExpressionTree<T>>(specs: &Specs, contents: &str, target: Option<usize>) {
+    let points: Vec<Point<T>> = string_to_points(contents, target).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1); }
+    );
+    let primitives = PrimitiveSet::from_names(&specs.operators).unwrap();
+    let seeds: Vec<Expr<T>> = specs.seed_expressions.iter().map(|expr| {
+        parse_expr(expr, &primitives).unwrap_or_else(|err| {
+            eprintln!("seed_expression = {} can't be parsed.\nerror: {}\n", expr, err);
+            process::exit(1); }
+        )
+    }).collect();
+    let mut context = Context::new(points[0].dimensions(), primitives);
+    context.max_depth = specs.max_depth;
+    context.jit = specs.jit;
+    context.unbounded_penalty = specs.unbounded_penalty;
+    context.linear_scaling = specs.linear_scaling;
+    context.constant_range = specs.constant_range;
+    context.constant_sampling = specs.constant_sampling.clone();
 
     // Run simulation
-    let mut sim = Simulation::new(
-        iterations,
-        crossover_probability, 
-        mutation_probability, 
-        population_size,
-        points
+    let mut sim: Simulation<T, D> = Simulation::new(
+        specs.iterations,
+        specs.crossover_probability, 
+        specs.mutation_probability, 
+        specs.population_size,
+        points,
+        context
+    ).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1); }
+    );
+    sim.simplify_every = specs.simplify_every;
+    sim.optimize_iterations = specs.optimize_iterations;
+    sim.optimize_every = specs.optimize_every;
+    sim.optimize_top = specs.optimize_top;
+    sim.initialization = specs.initialization;
+    sim.require_variable = specs.require_variable;
+    sim.seed_share = specs.seed_share;
+    sim.mutation_weights = specs.mutation_weights;
+    sim.adaptive_mutation = specs.adaptive_mutation;
+    sim.add_seeds(&seeds).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1); }
+    );
+    if let Some(seed) = specs.seed {
+        sim.seed = seed;
+    }
+    sim.run(specs.debug_level, specs.skip).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1); }
     );
-    sim.run(debug_level, skip);
 }
@@ -0,0 +1,220 @@
+extern crate rand;
+
+use self::rand::Rng;
+
+/// Number of mutation operators
+pub const MUTATIONS: usize = 9;
+/// Share of an even split that adaptive selection always leaves each enabled operator
+const MIN_SHARE: f64 = 0.2;
+/// How far each outcome moves an operator's estimated success rate
+const ADAPTATION_RATE: f64 = 0.05;
+/// Success rate every operator is estimated at before its first outcome
+const INITIAL_SUCCESS_RATE: f64 = 0.5;
+
+/// Mutation operators of ExpressionTree
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mutation {
+    Constant,
+    Clip,
+    Swap,
+    Similar,
+    Hoist,
+    Shrink,
+    Insert,
+    Permutation,
+    Terminal
+}
+
+impl Mutation {
+    pub const ALL: [Mutation; MUTATIONS] = [
+        Mutation::Constant, Mutation::Clip, Mutation::Swap, Mutation::Similar,
+        Mutation::Hoist, Mutation::Shrink, Mutation::Insert, Mutation::Permutation,
+        Mutation::Terminal
+    ];
+
+    /// Name as written in specs files
+    pub fn name(self) -> &'static str {
+        match self {
+            Mutation::Constant => "constant",
+            Mutation::Clip => "clip",
+            Mutation::Swap => "swap",
+            Mutation::Similar => "similar",
+            Mutation::Hoist => "hoist",
+            Mutation::Shrink => "shrink",
+            Mutation::Insert => "insert",
+            Mutation::Permutation => "permutation",
+            Mutation::Terminal => "terminal"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Mutation::ALL.iter().cloned().find(|mutation| mutation.name() == name)
+    }
+
+    /// Whether the operator has something to act on in a heap of depth
+    /// Clip, swap, hoist and shrink need operators below the top one, and otherwise fall back
+    /// on mutate_constant
+    pub fn applies_at(self, depth: u32) -> bool {
+        depth > 2 || matches!(self, Mutation::Constant | Mutation::Similar | Mutation::Insert |
+                                    Mutation::Permutation | Mutation::Terminal)
+    }
+}
+
+/// Picks mutation operators with probabilities proportional to their weights
+/// In adaptive mode, weights are scaled by each operator's recent rate of improving fitness,
+/// which is probability matching, and every operator with a positive weight keeps
+/// at least MIN_SHARE of an even split so it can recover
+#[derive(Debug, Clone)]
+pub struct MutationSelector {
+    weights: [f64; MUTATIONS],
+    adaptive: bool,
+    success_rates: [f64; MUTATIONS],
+    uses: [usize; MUTATIONS],
+    improvements: [usize; MUTATIONS]
+}
+
+impl MutationSelector {
+    /// Weights are indexed like Mutation::ALL, fails unless they are finite and non-negative
+    /// with at least one positive
+    pub fn new(weights: [f64; MUTATIONS], adaptive: bool) -> Result<Self, String> {
+        if let Some(i) = weights.iter().position(|&w| !(w.is_finite() && w >= 0.0)) {
+            return Err(format!("{} mutation weight {} must be a non-negative number",
+                               Mutation::ALL[i].name(), weights[i]));
+        }
+        if weights.iter().all(|&w| w == 0.0) {
+            return Err("At least one mutation weight must be positive".to_string());
+        }
+        Ok(MutationSelector {weights, adaptive,
+                             success_rates: [INITIAL_SUCCESS_RATE; MUTATIONS],
+                             uses: [0; MUTATIONS], improvements: [0; MUTATIONS]})
+    }
+
+    /// Current probability of picking each operator, indexed like Mutation::ALL
+    pub fn probabilities(&self) -> [f64; MUTATIONS] {
+        let mut probabilities = [0.0; MUTATIONS];
+        if !self.adaptive {
+            let total: f64 = self.weights.iter().sum();
+            for (p, w) in probabilities.iter_mut().zip(&self.weights) {
+                *p = w / total;
+            }
+            return probabilities;
+        }
+        let enabled = self.weights.iter().filter(|&&w| w > 0.0).count() as f64;
+        let floor = MIN_SHARE / enabled;
+        let scores: Vec<f64> = self.weights.iter().zip(&self.success_rates)
+            .map(|(w, rate)| w * rate)
+            .collect();
+        let total: f64 = scores.iter().sum();
+        for (i, p) in probabilities.iter_mut().enumerate() {
+            if self.weights[i] == 0.0 {
+                continue;
+            }
+            let share = if total > 0.0 {scores[i] / total} else {1.0 / enabled};
+            *p = floor + (1.0 - MIN_SHARE) * share;
+        }
+        probabilities
+    }
+
+    /// Picks an operator for a heap of depth, among those that apply to it if any is enabled
+    pub fn choose<R: Rng>(&self, depth: u32, rng: &mut R) -> Mutation {
+        let probabilities = self.probabilities();
+        let applicable = |i: usize| Mutation::ALL[i].applies_at(depth) && probabilities[i] > 0.0;
+        let restrict = (0..MUTATIONS).any(applicable);
+        let candidates: Vec<usize> = (0..MUTATIONS)
+            .filter(|&i| probabilities[i] > 0.0 && (!restrict || applicable(i)))
+            .collect();
+        let total: f64 = candidates.iter().map(|&i| probabilities[i]).sum();
+        let mut r = rng.gen_range(0.0, total);
+        for &i in &candidates {
+            if r < probabilities[i] {
+                return Mutation::ALL[i];
+            }
+            r -= probabilities[i];
+        }
+        Mutation::ALL[*candidates.last().unwrap()] // Rounding left r just short of total
+    }
+
+    /// Records whether a mutation improved fitness, which adaptive mode learns from
+    pub fn record(&mut self, mutation: Mutation, improved: bool) {
+        let i = mutation as usize;
+        self.uses[i] += 1;
+        if improved {
+            self.improvements[i] += 1;
+        }
+        let reward = if improved {1.0} else {0.0};
+        self.success_rates[i] += ADAPTATION_RATE * (reward - self.success_rates[i]);
+    }
+
+    /// Times an operator was recorded, and how many of those improved fitness
+    pub fn outcomes(&self, mutation: Mutation) -> (usize, usize) {
+        (self.uses[mutation as usize], self.improvements[mutation as usize])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate rand;
+
+    use self::rand::SeedableRng;
+    use self::rand::rngs::StdRng;
+
+    use super::*;
+
+    fn assert_floor(selector: &MutationSelector, enabled: usize) {
+        let probabilities = selector.probabilities();
+        let total: f64 = probabilities.iter().sum();
+        assert!((total - 1.0).abs() < 1e-12, "{:?}", probabilities);
+        for (i, &p) in probabilities.iter().enumerate() {
+            if selector.weights[i] > 0.0 {
+                assert!(p >= MIN_SHARE / enabled as f64 - 1e-12, "{:?}", probabilities);
+            } else {
+                assert_eq!(p, 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn adapts_toward_improving_operators() {
+        let mut weights = [1.0; MUTATIONS];
+        weights[Mutation::Hoist as usize] = 0.0;
+        let mut selector = MutationSelector::new(weights, true).unwrap();
+        assert_floor(&selector, MUTATIONS - 1);
+        let before = selector.probabilities()[Mutation::Swap as usize];
+        for _ in 0..200 {
+            for &mutation in Mutation::ALL.iter() {
+                selector.record(mutation, mutation == Mutation::Swap);
+            }
+        }
+        let after = selector.probabilities();
+        assert!(after[Mutation::Swap as usize] > before);
+        assert!(after[Mutation::Swap as usize] > 0.75, "{:?}", after);
+        assert_floor(&selector, MUTATIONS - 1);
+        assert_eq!(selector.outcomes(Mutation::Swap), (200, 200));
+        assert_eq!(selector.outcomes(Mutation::Clip), (200, 0));
+        for _ in 0..200 { // Nothing improves, so every enabled operator keeps its floor
+            for &mutation in Mutation::ALL.iter() {
+                selector.record(mutation, false);
+            }
+        }
+        assert_floor(&selector, MUTATIONS - 1);
+    }
+
+    #[test]
+    fn fixed_weights_ignore_outcomes() {
+        let mut weights = [0.0; MUTATIONS];
+        weights[Mutation::Constant as usize] = 3.0;
+        weights[Mutation::Clip as usize] = 1.0;
+        let mut selector = MutationSelector::new(weights, false).unwrap();
+        selector.record(Mutation::Clip, true);
+        let probabilities = selector.probabilities();
+        assert_eq!(probabilities[Mutation::Constant as usize], 0.75);
+        assert_eq!(probabilities[Mutation::Clip as usize], 0.25);
+        let mut rng = StdRng::seed_from_u64(24);
+        for _ in 0..100 { // Clip has nothing below the top operator to act on
+            assert_eq!(selector.choose(2, &mut rng), Mutation::Constant);
+        }
+        weights[Mutation::Swap as usize] = -1.0;
+        assert!(MutationSelector::new(weights, false).is_err());
+        assert!(MutationSelector::new([0.0; MUTATIONS], true).is_err());
+    }
+}
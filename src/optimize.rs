@@ -0,0 +1,323 @@
+use std::cmp::Ordering;
+
+use super::{ArenaTree, Float, Instruction, Node, Point, Program, SymbolicBinaryHeap};
+
+/// Damping of a first Levenberg–Marquardt step, relative to the curvature
+const INITIAL_DAMPING: f64 = 1e-3;
+/// Past this damping steps are too short to lower the error, so optimization stops
+const MAX_DAMPING: f64 = 1e12;
+/// Stop once an iteration lowers the squared error by less than this fraction
+const TOLERANCE: f64 = 1e-12;
+
+/// Derivatives of an operator's result with respect to its padded operands l and r
+/// Follows the builtin definitions, protected cases included, so they are zero where the
+/// function is flat and at the points protection patches; custom operators are
+/// differentiated numerically by central differences
+fn partials<T: Float>(program: &Program<T>, op: Node<T>, l: T, r: T) -> (T, T) {
+    let x = l + r; // The operand of unary operators
+    let two = T::from_f64(2.0);
+    let sign = |x: T| if x > T::ZERO {T::ONE} else if x < T::ZERO {-T::ONE} else {T::ZERO};
+    let unary = |derivative: T| (derivative, derivative);
+    match op {
+        Node::Add => (T::ONE, T::ONE),
+        Node::Subtract => (T::ONE, -T::ONE),
+        Node::Multiply => (r, l),
+        Node::Divide => if r != T::ZERO {
+            (T::ONE / r, -l / (r * r))
+        } else {
+            (T::ONE / T::MIN_POSITIVE, T::ZERO)
+        },
+        Node::Sine => unary(x.cos()),
+        Node::Cosine => unary(-x.sin()),
+        Node::Exp => unary(x.exp()),
+        Node::Log => unary(if x != T::ZERO {T::ONE / x} else {T::ZERO}),
+        Node::Sqrt => unary(if x != T::ZERO {sign(x) / (two * x.abs().sqrt())} else {T::ZERO}),
+        Node::Pow => { // |l|^r
+            if l == T::ZERO {
+                return (T::ZERO, T::ZERO);
+            }
+            let value = l.abs().powf(r);
+            (r * value / l, value * l.abs().ln()) },
+        Node::Abs => unary(sign(x)),
+        Node::Tanh => {
+            let t = x.tanh();
+            unary(T::ONE - t * t) },
+        Node::Square => unary(two * x),
+        Node::Negate => unary(-T::ONE),
+        Node::Sign => unary(T::ZERO),
+        Node::Min => if l <= r {(T::ONE, T::ZERO)} else {(T::ZERO, T::ONE)},
+        Node::Max => if l >= r {(T::ONE, T::ZERO)} else {(T::ZERO, T::ONE)},
+        _ => {
+            let step = |v: T| T::from_f64(T::EPSILON.to_f64().cbrt() * v.to_f64().abs().max(1.0));
+            let primitives = &program.context.primitives;
+            let (h, k) = (step(l), step(r));
+            ((primitives.apply(op, l + h, r) - primitives.apply(op, l - h, r)) / (h + h),
+             (primitives.apply(op, l, r + k) - primitives.apply(op, l, r - k)) / (k + k)) }
+    }
+}
+
+/// Evaluates the program at one point in forward mode
+/// Returns its value and gradient with respect to each Number instruction, in program order
+fn forward<T: Float>(program: &Program<T>, constants: usize, variables: &[T]) -> (T, Vec<T>) {
+    let zeros = || vec![T::ZERO; constants];
+    // Zero gradients stay zero, even times the huge slopes of protected division
+    let chain = |derivative: T, gradient: T| {
+        if gradient == T::ZERO {T::ZERO} else {derivative * gradient}
+    };
+    let mut stack: Vec<(T, Vec<T>)> = Vec::with_capacity(program.instructions.len());
+    let mut next_constant = 0;
+    for instruction in &program.instructions {
+        let entry = match *instruction {
+            Instruction::Number(n) => {
+                let mut gradient = zeros();
+                gradient[next_constant] = T::ONE;
+                next_constant += 1;
+                (n, gradient) },
+            Instruction::Variable(i) => (variables[i], zeros()),
+            Instruction::Operator(op, has_left, has_right) => {
+                let padded = |entry: Option<(T, Vec<T>)>| match entry {
+                    Some((value, gradient)) => (T::MIN_POSITIVE + value, gradient),
+                    None => (T::MIN_POSITIVE, zeros())
+                };
+                let right = padded(if has_right {stack.pop()} else {None});
+                let left = padded(if has_left {stack.pop()} else {None});
+                let value = program.context.primitives.apply(op, left.0, right.0);
+                let (dl, dr) = partials(program, op, left.0, right.0);
+                let gradient = left.1.iter().zip(&right.1)
+                    .map(|(&a, &b)| chain(dl, a) + chain(dr, b))
+                    .collect();
+                (value, gradient) }
+        };
+        stack.push(entry);
+    }
+    stack.pop().unwrap()
+}
+
+/// Sum of the squared error at each point, infinite rather than NaN
+fn squared_error<T: Float>(program: &Program<T>, points: &[Point<T>]) -> f64 {
+    let error: f64 = program.evaluate_points(points).iter().zip(points)
+        .map(|(&prediction, point)| (point.y - prediction).to_f64().powi(2))
+        .sum();
+    if error.is_nan() {f64::INFINITY} else {error}
+}
+
+/// Solves a x = b by Gaussian elimination with partial pivoting, None if a is singular
+fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| {
+            a[i][col].abs().partial_cmp(&a[j][col].abs()).unwrap_or(Ordering::Equal)
+        })?;
+        if a[pivot][col] == 0.0 || !a[pivot][col].is_finite() {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        let (upper, lower) = a.split_at_mut(col + 1);
+        let pivot_row = &upper[col];
+        for (offset, row) in lower.iter_mut().enumerate() {
+            let factor = row[col] / pivot_row[col];
+            for (value, &pivot_value) in row.iter_mut().zip(pivot_row).skip(col) {
+                *value -= factor * pivot_value;
+            }
+            b[col + 1 + offset] -= factor * b[col];
+        }
+    }
+    let mut x: Vec<f64> = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    if x.iter().all(|value| value.is_finite()) {Some(x)} else {None}
+}
+
+/// Levenberg–Marquardt steps tuning the constants of program, see
+/// SymbolicBinaryHeap::optimize_constants
+/// Returns the constants in program order if they lower the error, and the passes spent
+fn tune_constants<T: Float>(mut program: Program<T>, points: &[Point<T>], iterations: usize)
+                                                               -> (Option<Vec<f64>>, usize) {
+    let positions: Vec<usize> = program.instructions.iter().enumerate()
+        .filter(|(_, instruction)| matches!(instruction, Instruction::Number(_)))
+        .map(|(position, _)| position)
+        .collect();
+    let set_constants = |program: &mut Program<T>, constants: &[f64]| {
+        for (&position, &constant) in positions.iter().zip(constants) {
+            program.instructions[position] = Instruction::Number(T::from_f64(constant));
+        }
+    };
+    let k = positions.len();
+    let mut constants: Vec<f64> = positions.iter().map(|&position| {
+        match program.instructions[position] {
+            Instruction::Number(n) => n.to_f64(),
+            _ => 0.0
+        }
+    }).collect();
+    let initial_error = squared_error(&program, points);
+    let mut error = initial_error;
+    let mut evaluations: usize = 1;
+    let mut damping = INITIAL_DAMPING;
+    for _ in 0..iterations {
+        // Normal equations of the linearized residuals, prediction - y
+        let mut jtj: Vec<Vec<f64>> = vec![vec![0.0; k]; k];
+        let mut jtr: Vec<f64> = vec![0.0; k];
+        for point in points {
+            let (prediction, gradient) = forward(&program, k, &point.x);
+            let residual = (prediction - point.y).to_f64();
+            if !residual.is_finite() {
+                continue;
+            }
+            let gradient: Vec<f64> = gradient.iter().map(|g| {
+                let g = g.to_f64();
+                if g.is_finite() {g} else {0.0}
+            }).collect();
+            for (a, row) in jtj.iter_mut().enumerate() {
+                jtr[a] += gradient[a] * residual;
+                for (value, &g) in row.iter_mut().zip(&gradient) {
+                    *value += gradient[a] * g;
+                }
+            }
+        }
+        evaluations += 1;
+        let previous_error = error;
+        while damping <= MAX_DAMPING {
+            let mut system = jtj.clone();
+            for (a, row) in system.iter_mut().enumerate() {
+                row[a] += damping * jtj[a][a].max(f64::MIN_POSITIVE);
+            }
+            let step = solve(system, jtr.iter().map(|value| -value).collect());
+            damping *= 10.0;
+            let trial: Vec<f64> = match step {
+                Some(step) => constants.iter().zip(&step)
+                    .map(|(constant, delta)| T::from_f64(constant + delta).to_f64())
+                    .collect(),
+                None => continue
+            };
+            set_constants(&mut program, &trial);
+            let trial_error = squared_error(&program, points);
+            evaluations += 1;
+            if trial_error < error {
+                constants = trial;
+                error = trial_error;
+                damping /= 100.0;
+                break;
+            }
+        }
+        set_constants(&mut program, &constants);
+        let converged = previous_error.is_finite() &&
+                        previous_error - error <= TOLERANCE * previous_error;
+        if error >= previous_error || error == 0.0 || converged {
+            break;
+        }
+    }
+    let tuned = if error < initial_error {Some(constants)} else {None};
+    (tuned, evaluations)
+}
+
+impl<T: Float> SymbolicBinaryHeap<T> {
+    /// Heap indices of the constants, in the order compile emits them
+    fn constant_idxs(&self) -> Vec<usize> {
+        let mut idxs: Vec<usize> = Vec::new();
+        if self.heap[0].is_some() {
+            self._constant_idxs(0, &mut idxs);
+        }
+        idxs
+    }
+
+    fn _constant_idxs(&self, idx: usize, idxs: &mut Vec<usize>) {
+        match self.heap[idx] {
+            Some(Node::Number(_)) => idxs.push(idx),
+            Some(Node::Variable(_)) | None => (),
+            Some(_) => {
+                let left_idx = 2 * idx + 1;
+                let right_idx = left_idx + 1;
+                if left_idx < self.max_idx() && self.heap[left_idx].is_some() {
+                    self._constant_idxs(left_idx, idxs);
+                }
+                if right_idx <= self.max_idx() && self.heap[right_idx].is_some() {
+                    self._constant_idxs(right_idx, idxs);
+                } }
+        }
+    }
+
+    /// Tunes every Node::Number to minimise the squared error over points, with at most
+    /// iterations Levenberg–Marquardt steps on derivatives computed in forward mode
+    /// The constants only change if the error drops, and are no longer bounded by the
+    /// range mutation keeps them in
+    /// Returns the number of passes over the points spent, each as costly as a fitness
+    pub fn optimize_constants(&mut self, points: &[Point<T>], iterations: usize) -> usize {
+        let idxs = self.constant_idxs();
+        if idxs.is_empty() || points.is_empty() || iterations == 0 {
+            return 0;
+        }
+        let (tuned, evaluations) = tune_constants(self.compile(), points, iterations);
+        for (&idx, &constant) in idxs.iter().zip(tuned.iter().flatten()) {
+            self.heap[idx] = Some(Node::Number(T::from_f64(constant)));
+        }
+        evaluations
+    }
+}
+
+impl<T: Float> ArenaTree<T> {
+    /// Tunes every Node::Number, see SymbolicBinaryHeap::optimize_constants
+    /// Nodes are in preorder, so constants come in the order compile emits them
+    pub fn optimize_constants(&mut self, points: &[Point<T>], iterations: usize) -> usize {
+        let idxs: Vec<usize> = (0..self.nodes.len())
+            .filter(|&idx| matches!(self.nodes[idx].node, Node::Number(_)))
+            .collect();
+        if idxs.is_empty() || points.is_empty() || iterations == 0 {
+            return 0;
+        }
+        let (tuned, evaluations) = tune_constants(self.compile(), points, iterations);
+        for (&idx, &constant) in idxs.iter().zip(tuned.iter().flatten()) {
+            self.nodes[idx].node = Node::Number(T::from_f64(constant));
+        }
+        evaluations
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use super::*;
+    use super::super::{Context, PrimitiveSet};
+
+    fn line() -> Vec<Point<f64>> {
+        (0..10).map(|i| {
+            let x = f64::from(i) / 2.0 - 2.0;
+            Point::new(vec![x], 3.0 * x - 2.0)
+        }).collect()
+    }
+
+    fn error<F: Fn(&[f64]) -> f64>(collapse: F, points: &[Point<f64>]) -> f64 {
+        points.iter().map(|point| (collapse(&point.x) - point.y).powi(2)).sum()
+    }
+
+    #[test]
+    fn fits_a_line() {
+        let context = Rc::new(Context::new(1, PrimitiveSet::all()));
+        let points = line();
+        let mut heap = SymbolicBinaryHeap::parse("1.5 * x0 + 0.5", &context).unwrap();
+        let mut tree = ArenaTree::from_expr(&heap.to_expr(), &context);
+        let before = error(|x| heap.collapse(x), &points);
+        assert!(heap.optimize_constants(&points, 50) > 0);
+        assert!(tree.optimize_constants(&points, 50) > 0);
+        let after = error(|x| heap.collapse(x), &points);
+        assert!(after < before * 1e-12, "{} after {}", after, before);
+        assert!(error(|x| tree.collapse(x), &points) < before * 1e-12);
+        assert!((heap.collapse(&[0.0]) + 2.0).abs() < 1e-6);
+        assert!((heap.collapse(&[1.0]) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn keeps_constants_unless_error_drops() {
+        let context = Rc::new(Context::new(1, PrimitiveSet::all()));
+        let points = line();
+        let mut heap = SymbolicBinaryHeap::parse("3 * x0 - 2", &context).unwrap();
+        let exact = heap.clone();
+        heap.optimize_constants(&points, 50);
+        assert_eq!(heap.heap, exact.heap);
+        let mut no_constants = SymbolicBinaryHeap::parse("sin(x0)", &context).unwrap();
+        assert_eq!(no_constants.optimize_constants(&points, 50), 0);
+    }
+}
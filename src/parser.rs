@@ -0,0 +1,313 @@
+use std::rc::Rc;
+use std::str::FromStr;
+
+use super::{Context, Expr, Float, Node, PrimitiveSet, SymbolicBinaryHeap};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(String),
+    Name(String),
+    Symbol(char)
+}
+
+/// Splits an expression into tokens, each paired with its position in the string
+fn tokenize(s: &str) -> Result<Vec<(usize, Token)>, String> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens: Vec<(usize, Token)> = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            // Exponent, as in 6.674e-11
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                let mut j = i + 1;
+                if j < chars.len() && (chars[j] == '+' || chars[j] == '-') {
+                    j += 1;
+                }
+                if j < chars.len() && chars[j].is_ascii_digit() {
+                    i = j;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+            tokens.push((start, Token::Number(chars[start..i].iter().collect())));
+        } else if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push((start, Token::Name(chars[start..i].iter().collect())));
+        } else if "+-*/^(),".contains(c) {
+            tokens.push((start, Token::Symbol(c)));
+            i += 1;
+        } else {
+            return Err(format!("Unexpected character {:?} at position {}", c, start));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser over the grammar
+///     sum     := product (('+' | '-') product)*
+///     product := unary (('*' | '/') unary)*
+///     unary   := '-' unary | power
+///     power   := atom ('^' unary)?
+///     atom    := number | variable | name '(' sum (',' sum)? ')' | '(' sum ')'
+struct Parser<'a, T: 'a> {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    end: usize,
+    primitives: &'a PrimitiveSet<T>
+}
+
+impl<'a, T: Float> Parser<'a, T> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    /// Position in the string of the next token, for error messages
+    fn position(&self) -> usize {
+        self.tokens.get(self.pos).map_or(self.end, |(position, _)| *position)
+    }
+
+    fn describe_next(&self) -> String {
+        match self.peek() {
+            Some(Token::Number(s)) | Some(Token::Name(s)) => format!("{:?}", s),
+            Some(Token::Symbol(c)) => format!("{:?}", c),
+            None => "end of input".to_string()
+        }
+    }
+
+    fn accept(&mut self, symbol: char) -> bool {
+        if self.peek() == Some(&Token::Symbol(symbol)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), String> {
+        if self.accept(symbol) {
+            Ok(())
+        } else {
+            Err(format!("Expected {:?} but found {} at position {}",
+                        symbol, self.describe_next(), self.position()))
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expr<T>, String> {
+        let mut expr = self.product()?;
+        loop {
+            let op = if self.accept('+') {
+                Node::Add
+            } else if self.accept('-') {
+                Node::Subtract
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::binary(op, expr, self.product()?);
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr<T>, String> {
+        let mut expr = self.unary()?;
+        loop {
+            let op = if self.accept('*') {
+                Node::Multiply
+            } else if self.accept('/') {
+                Node::Divide
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::binary(op, expr, self.unary()?);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr<T>, String> {
+        if self.accept('-') {
+            // Negative literals are folded into the constant, but -(2) stays a negation
+            let literal = matches!(self.peek(), Some(Token::Number(_)));
+            return Ok(match self.unary()? {
+                Expr::Terminal(Node::Number(n)) if literal => Expr::number(-n),
+                operand => Expr::unary(Node::Negate, operand)
+            });
+        }
+        self.power()
+    }
+
+    fn power(&mut self) -> Result<Expr<T>, String> {
+        let base = self.atom()?;
+        if self.accept('^') {
+            return Ok(Expr::binary(Node::Pow, base, self.unary()?));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<Expr<T>, String> {
+        let position = self.position();
+        match self.peek().cloned() {
+            Some(Token::Number(s)) => {
+                self.pos += 1;
+                let n: T = s.parse().map_err(|_| {
+                    format!("Invalid number {:?} at position {}", s, position)
+                })?;
+                Ok(Expr::number(n)) },
+            Some(Token::Name(name)) => {
+                self.pos += 1;
+                if let Some(i) = parse_variable(&name) {
+                    return Ok(Expr::variable(i));
+                }
+                let op = self.primitives.find(&name).ok_or_else(|| {
+                    format!("Unknown function or variable {:?} at position {}", name, position)
+                })?;
+                self.expect('(')?;
+                let mut args = vec![self.sum()?];
+                while self.accept(',') {
+                    args.push(self.sum()?);
+                }
+                self.expect(')')?;
+                let arity = self.primitives.arity(op);
+                if args.len() != arity {
+                    return Err(format!("{} takes {} argument(s) but {} were given at position {}",
+                                       name, arity, args.len(), position));
+                }
+                let first = args.remove(0);
+                Ok(match args.pop() {
+                    Some(second) => Expr::binary(op, first, second),
+                    None => Expr::unary(op, first)
+                }) },
+            Some(Token::Symbol('(')) => {
+                self.pos += 1;
+                let expr = self.sum()?;
+                self.expect(')')?;
+                Ok(expr) },
+            _ => Err(format!("Unexpected {} at position {}", self.describe_next(), position))
+        }
+    }
+}
+
+/// Variables are written x, or x0, x1, ... with several inputs
+pub(crate) fn parse_variable(name: &str) -> Option<usize> {
+    if name == "x" {
+        return Some(0);
+    }
+    if !name.starts_with('x') || name.len() < 2 {
+        return None;
+    }
+    name[1..].parse().ok()
+}
+
+/// Parses an infix expression, looking up function names among the primitives
+pub fn parse_expr<T: Float>(s: &str, primitives: &PrimitiveSet<T>) -> Result<Expr<T>, String> {
+    let tokens = tokenize(s)?;
+    let mut parser = Parser {tokens, pos: 0, end: s.chars().count(), primitives};
+    let expr = parser.sum()?;
+    if parser.peek().is_some() {
+        return Err(format!("Unexpected {} at position {}",
+                           parser.describe_next(), parser.position()));
+    }
+    Ok(expr)
+}
+
+impl<T: Float> SymbolicBinaryHeap<T> {
+    /// Parses an infix expression such as "2*x + 1" against a simulation's context
+    /// Every built-in and registered operator is accepted, enabled or not
+    /// Fails on unknown names, variables beyond the context's inputs or excess depth
+    pub fn parse(s: &str, context: &Rc<Context<T>>) -> Result<Self, String> {
+        let expr = parse_expr(s, &context.primitives)?;
+        if let Some(i) = expr.max_variable().filter(|&i| i >= context.variables) {
+            return Err(format!("Variable x{} is out of range for {} input variable(s)",
+                               i, context.variables));
+        }
+        SymbolicBinaryHeap::from_expr(&expr, context)
+    }
+}
+
+/// Parses with the default primitives and max_depth
+/// The number of input variables is taken from the highest variable used
+impl<T: Float> FromStr for SymbolicBinaryHeap<T> {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let primitives = PrimitiveSet::all();
+        let expr = parse_expr(s, &primitives)?;
+        let variables = expr.max_variable().map_or(1, |i| i + 1);
+        let context = Rc::new(Context::new(variables, primitives));
+        SymbolicBinaryHeap::from_expr(&expr, &context)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate rand;
+
+    use self::rand::SeedableRng;
+    use self::rand::rngs::StdRng;
+    use super::*;
+
+    fn context(variables: usize, max_depth: u32) -> Rc<Context<f64>> {
+        let mut context = Context::new(variables, PrimitiveSet::all());
+        context.max_depth = max_depth;
+        Rc::new(context)
+    }
+
+    #[test]
+    fn rejects_unknown_identifiers() {
+        let err = SymbolicBinaryHeap::parse("foo(x) + 1", &context(1, 6)).unwrap_err();
+        assert!(err.contains("Unknown function or variable \"foo\""), "{}", err);
+    }
+
+    #[test]
+    fn rejects_unbalanced_parentheses() {
+        for s in &["(x + 1", "x + 1)", "sin(x", "((x)"] {
+            assert!(SymbolicBinaryHeap::parse(s, &context(1, 6)).is_err(), "{} parsed", s);
+        }
+    }
+
+    #[test]
+    fn rejects_variables_beyond_the_dataset() {
+        let err = SymbolicBinaryHeap::parse("x0 + x2", &context(2, 6)).unwrap_err();
+        assert!(err.contains("x2 is out of range"), "{}", err);
+        assert!(SymbolicBinaryHeap::parse("x0 + x1", &context(2, 6)).is_ok());
+    }
+
+    #[test]
+    fn rejects_expressions_deeper_than_max_depth() {
+        let err = SymbolicBinaryHeap::parse("sin(sin(sin(x)))", &context(1, 3)).unwrap_err();
+        assert!(err.contains("exceeds max_depth"), "{}", err);
+        assert!(SymbolicBinaryHeap::parse("sin(sin(x))", &context(1, 3)).is_ok());
+    }
+
+    #[test]
+    fn printed_heaps_parse_back() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for &variables in &[1, 3] {
+            let context = context(variables, 6);
+            for _ in 0..500 {
+                let mut heap = SymbolicBinaryHeap::new(&context);
+                heap.spawn(&mut rng).unwrap();
+                let printed = heap.to_string();
+                let parsed = SymbolicBinaryHeap::parse(&printed, &context).unwrap();
+                assert_eq!(parsed.to_expr(), heap.to_expr(), "{} parsed differently", printed);
+            }
+        }
+    }
+
+    #[test]
+    fn printed_exprs_parse_back_at_any_depth() {
+        let context = context(2, 3);
+        for s in &["sin(sin(sin(sin(x0))))", "-(2) * (x0 - x1) ^ 2 ^ x1", "min(x0, -x1) / 3"] {
+            let expr = parse_expr(s, &context.primitives).unwrap();
+            let printed = expr.display(&context).to_string();
+            assert_eq!(parse_expr(&printed, &context.primitives).unwrap(), expr,
+                       "{} printed as {}", s, printed);
+        }
+    }
+}
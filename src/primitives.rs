@@ -0,0 +1,270 @@
+use std::fmt;
+use std::rc::Rc;
+
+use super::{Float, Node};
+use parser::parse_variable;
+
+/// Operator names accepted by PrimitiveSet::from_names, in table order
+pub const BUILTIN_NAMES: [&str; 17] = [
+    "add", "sub", "mul", "div", "sin", "cos", "exp", "log",
+    "sqrt", "pow", "abs", "tanh", "square", "neg", "min", "max", "sign"
+];
+
+/// The operators enabled when none are specified
+pub const DEFAULT_NAMES: [&str; 6] = ["add", "sub", "mul", "div", "sin", "cos"];
+
+/// How an operator is evaluated, which also determines its arity
+#[derive(Clone)]
+pub enum Eval<T> {
+    Unary(Rc<dyn Fn(T) -> T>),
+    Binary(Rc<dyn Fn(T, T) -> T>)
+}
+
+impl<T> fmt::Debug for Eval<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Eval::Unary(_) => write!(f, "Unary"),
+            Eval::Binary(_) => write!(f, "Binary")
+        }
+    }
+}
+
+/// Declares an operator node: its printable name, evaluation and "similar" partner
+/// The similar partner is what mutate_similar replaces the operator with
+#[derive(Debug, Clone)]
+pub struct Primitive<T> {
+    pub node: Node<T>,
+    pub name: String,
+    pub eval: Eval<T>,
+    pub similar: Option<Node<T>>,
+    /// Bounds on the output of a custom operator, see PrimitiveSet::set_range
+    pub range: Option<(f64, f64)>
+}
+
+impl<T> Primitive<T> {
+    pub fn arity(&self) -> usize {
+        match self.eval {
+            Eval::Unary(_) => 1,
+            Eval::Binary(_) => 2
+        }
+    }
+}
+
+fn protected_divide<T: Float>(l: T, r: T) -> T {
+    if r != T::ZERO {l / r} else {l / T::MIN_POSITIVE}
+}
+
+pub(crate) fn protected_log<T: Float>(x: T) -> T {
+    if x != T::ZERO {x.abs().ln()} else {T::ZERO}
+}
+
+/// -1, 0 or 1 as x is negative, zero or positive, so the derivative of abs
+pub(crate) fn sign<T: Float>(x: T) -> T {
+    if x > T::ZERO {T::ONE} else if x < T::ZERO {-T::ONE} else {T::ZERO}
+}
+
+fn unary<T: Float>(node: Node<T>, name: &str, f: fn(T) -> T, similar: Node<T>)
+                                                                    -> Primitive<T> {
+    Primitive {node, name: name.to_string(), eval: Eval::Unary(Rc::new(f)),
+               similar: Some(similar), range: None}
+}
+
+fn binary<T: Float>(node: Node<T>, name: &str, f: fn(T, T) -> T, similar: Node<T>)
+                                                                           -> Primitive<T> {
+    Primitive {node, name: name.to_string(), eval: Eval::Binary(Rc::new(f)),
+               similar: Some(similar), range: None}
+}
+
+/// Built-in operators, indexed by Node::op_index
+fn builtins<T: Float>() -> Vec<Primitive<T>> {
+    vec![
+        binary(Node::Add, "add", |l, r| l + r, Node::Subtract),
+        binary(Node::Subtract, "sub", |l, r| l - r, Node::Add),
+        binary(Node::Multiply, "mul", |l, r| l * r, Node::Divide),
+        binary(Node::Divide, "div", protected_divide, Node::Multiply),
+        unary(Node::Sine, "sin", T::sin, Node::Cosine),
+        unary(Node::Cosine, "cos", T::cos, Node::Sine),
+        unary(Node::Exp, "exp", T::exp, Node::Log),
+        unary(Node::Log, "log", protected_log, Node::Exp),
+        unary(Node::Sqrt, "sqrt", |x: T| x.abs().sqrt(), Node::Square),
+        binary(Node::Pow, "pow", |l: T, r: T| l.abs().powf(r), Node::Multiply),
+        unary(Node::Abs, "abs", T::abs, Node::Negate),
+        unary(Node::Tanh, "tanh", T::tanh, Node::Sine),
+        unary(Node::Square, "square", |x| x * x, Node::Sqrt),
+        unary(Node::Negate, "neg", |x| -x, Node::Abs),
+        binary(Node::Min, "min", T::min, Node::Max),
+        binary(Node::Max, "max", T::max, Node::Min),
+        unary(Node::Sign, "sign", sign, Node::Tanh)
+    ]
+}
+
+/// The operators available to random generation, mutation and evaluation
+#[derive(Debug, Clone)]
+pub struct PrimitiveSet<T> {
+    primitives: Vec<Primitive<T>>,
+    enabled: Vec<bool>
+}
+
+impl<T: Float> PrimitiveSet<T> {
+    /// Enables every built-in operator
+    pub fn all() -> Self {
+        let primitives = builtins();
+        let enabled = vec![true; primitives.len()];
+        PrimitiveSet {primitives, enabled}
+    }
+
+    /// Enables only the named operators, which must include a binary operator
+    pub fn from_names<S: AsRef<str>>(names: &[S]) -> Result<Self, String> {
+        let mut set = PrimitiveSet::all();
+        set.enabled = vec![false; set.primitives.len()];
+        for name in names {
+            let name = name.as_ref().trim();
+            let idx = BUILTIN_NAMES.iter().position(|&n| n == name).ok_or_else(|| {
+                format!("Unknown operator {:?}, expected one of {:?}", name, BUILTIN_NAMES)
+            })?;
+            set.enabled[idx] = true;
+        }
+        if set.enabled_with_arity(2).is_empty() {
+            return Err("At least one binary operator must be enabled".to_string());
+        }
+        Ok(set)
+    }
+
+    /// Registers and enables a named unary operator, returning its Node::Custom
+    pub fn register_unary<F>(&mut self, name: &str, f: F) -> Result<Node<T>, String>
+            where F: Fn(T) -> T + 'static {
+        self.register(name, Eval::Unary(Rc::new(f)))
+    }
+
+    /// Registers and enables a named binary operator, returning its Node::Custom
+    pub fn register_binary<F>(&mut self, name: &str, f: F) -> Result<Node<T>, String>
+            where F: Fn(T, T) -> T + 'static {
+        self.register(name, Eval::Binary(Rc::new(f)))
+    }
+
+    /// Fails unless name is an identifier the parser reads back as this operator: letters,
+    /// digits and underscores, not starting with a digit, and neither a variable nor taken
+    fn register(&mut self, name: &str, eval: Eval<T>) -> Result<Node<T>, String> {
+        let mut chars = name.chars();
+        let identifier = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_') &&
+                         chars.all(|c| c.is_alphanumeric() || c == '_');
+        if !identifier {
+            return Err(format!("Operator name {:?} must be letters, digits and underscores, \
+                                not starting with a digit", name));
+        }
+        if parse_variable(name).is_some() {
+            return Err(format!("Operator name {:?} would be read as a variable", name));
+        }
+        if BUILTIN_NAMES.contains(&name) {
+            return Err(format!("Operator name {:?} is taken by a built-in operator", name));
+        }
+        if self.find(name).is_some() {
+            return Err(format!("An operator named {:?} already exists", name));
+        }
+        let node = Node::Custom(self.primitives.len() - BUILTIN_NAMES.len());
+        self.primitives.push(Primitive {node, name: name.to_string(), eval, similar: None,
+                                        range: None});
+        self.enabled.push(true);
+        Ok(node)
+    }
+
+    /// Sets what mutate_similar replaces an operator with, which must have the same arity
+    pub fn set_similar(&mut self, node: Node<T>, similar: Node<T>) -> Result<(), String> {
+        if self.arity(node) == 0 || self.arity(node) != self.arity(similar) {
+            return Err(format!("{:?} and {:?} are not operators of the same arity",
+                               node, similar));
+        }
+        let idx = node.op_index().unwrap();
+        self.primitives[idx].similar = Some(similar);
+        Ok(())
+    }
+
+    /// Declares the (min, max) bounds of a custom operator's output, which may be infinite
+    /// Interval evaluation otherwise knows nothing about custom operators, see IntervalReport
+    pub fn set_range(&mut self, node: Node<T>, range: (f64, f64)) -> Result<(), String> {
+        let idx = match node {
+            Node::Custom(_) if self.get(node).is_some() => node.op_index().unwrap(),
+            _ => return Err(format!("{:?} is not a registered custom operator", node))
+        };
+        let (min, max) = range;
+        if min.is_nan() || max.is_nan() || min > max {
+            return Err(format!("range {:?} must have min <= max", range));
+        }
+        self.primitives[idx].range = Some(range);
+        Ok(())
+    }
+
+    /// Returns the operator node with a given name
+    pub fn find(&self, name: &str) -> Option<Node<T>> {
+        self.primitives.iter()
+                       .find(|primitive| primitive.name == name)
+                       .map(|primitive| primitive.node)
+    }
+
+    pub fn enable(&mut self, node: Node<T>) {
+        if let Some(enabled) = node.op_index().and_then(|idx| self.enabled.get_mut(idx)) {
+            *enabled = true;
+        }
+    }
+
+    /// Disabling is refused if it would leave no binary operator
+    pub fn disable(&mut self, node: Node<T>) -> Result<(), String> {
+        if let Some(idx) = node.op_index().filter(|&idx| idx < self.enabled.len()) {
+            self.enabled[idx] = false;
+            if self.enabled_with_arity(2).is_empty() {
+                self.enabled[idx] = true;
+                return Err("At least one binary operator must be enabled".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_enabled(&self, node: Node<T>) -> bool {
+        node.op_index().and_then(|idx| self.enabled.get(idx).copied()).unwrap_or(false)
+    }
+
+    /// Returns the declaration of an operator node, None for terminals
+    pub fn get(&self, node: Node<T>) -> Option<&Primitive<T>> {
+        node.op_index().and_then(|idx| self.primitives.get(idx))
+    }
+
+    /// Number of children an operator takes, 0 for terminals
+    pub fn arity(&self, node: Node<T>) -> usize {
+        self.get(node).map_or(0, |primitive| primitive.arity())
+    }
+
+    /// The similar partner of an operator, if that partner is enabled
+    pub fn similar(&self, node: Node<T>) -> Option<Node<T>> {
+        let similar = self.get(node)?.similar?;
+        if self.is_enabled(similar) {Some(similar)} else {None}
+    }
+
+    /// Enabled operator nodes, in table order
+    pub fn enabled_nodes(&self) -> Vec<Node<T>> {
+        self.primitives.iter()
+                       .zip(&self.enabled)
+                       .filter(|(_, &enabled)| enabled)
+                       .map(|(primitive, _)| primitive.node)
+                       .collect()
+    }
+
+    pub fn enabled_with_arity(&self, arity: usize) -> Vec<Node<T>> {
+        self.enabled_nodes().into_iter().filter(|&node| self.arity(node) == arity).collect()
+    }
+
+    /// Applies an operator to its (padded) child values
+    /// Unary operators take the sum of both children, as only one is ever present
+    pub fn apply(&self, node: Node<T>, l: T, r: T) -> T {
+        match self.get(node).map(|primitive| &primitive.eval) {
+            Some(Eval::Unary(f)) => f(l + r),
+            Some(Eval::Binary(f)) => f(l, r),
+            None => T::ZERO
+        }
+    }
+}
+
+impl<T: Float> Default for PrimitiveSet<T> {
+    fn default() -> Self {
+        PrimitiveSet::from_names(&DEFAULT_NAMES).unwrap()
+    }
+}
@@ -0,0 +1,280 @@
+use std::mem::take;
+use std::rc::Rc;
+
+use super::{ArenaTree, Context, Eval, Float, Node, Point, SymbolicBinaryHeap};
+
+/// One step of a postfix program
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction<T> {
+    Number(T),
+    Variable(usize),
+    /// An operator and whether its left and right children are present
+    /// Present children are popped from the stack, right first
+    Operator(Node<T>, bool, bool)
+}
+
+/// An expression tree flattened into postfix order, evaluated by a small stack machine
+/// Gives the same results as SymbolicBinaryHeap::collapse, padding included
+#[derive(Debug, Clone)]
+pub struct Program<T> {
+    pub instructions: Vec<Instruction<T>>,
+    pub context: Rc<Context<T>>
+}
+
+impl<T: Float> SymbolicBinaryHeap<T> {
+    /// Flattens the heap into a postfix program, visiting children exactly as _collapse does
+    pub fn compile(&self) -> Program<T> {
+        let mut instructions: Vec<Instruction<T>> = Vec::new();
+        match self.heap[0] {
+            Some(_) => self._compile(0, &mut instructions),
+            None => instructions.push(Instruction::Number(T::ZERO))
+        }
+        Program {instructions, context: Rc::clone(&self.context)}
+    }
+
+    fn _compile(&self, idx: usize, instructions: &mut Vec<Instruction<T>>) {
+        let node = self.heap[idx].unwrap();
+        let instruction = match node {
+            Node::Number(n) => Instruction::Number(n),
+            Node::Variable(i) => Instruction::Variable(i),
+            op => {
+                let left_idx = 2 * idx + 1;
+                let right_idx = left_idx + 1;
+                let has_left = left_idx < self.max_idx() && self.heap[left_idx].is_some();
+                let has_right = right_idx <= self.max_idx() && self.heap[right_idx].is_some();
+                if has_left {
+                    self._compile(left_idx, instructions);
+                }
+                if has_right {
+                    self._compile(right_idx, instructions);
+                }
+                Instruction::Operator(op, has_left, has_right) }
+        };
+        instructions.push(instruction);
+    }
+
+    /// Evaluates a single input expression, in x alone, over a column of x values
+    pub fn evaluate_batch(&self, x: &[T]) -> Vec<T> {
+        self.compile().evaluate_batch(&[x])
+    }
+
+    /// Evaluates over one column of values per input variable, see Program::evaluate_batch
+    pub fn evaluate_columns(&self, columns: &[&[T]]) -> Vec<T> {
+        self.compile().evaluate_batch(columns)
+    }
+
+    /// Evaluates at every point, as used by fitness
+    pub fn evaluate_points(&self, points: &[Point<T>]) -> Vec<T> {
+        self.compile().evaluate_points(points)
+    }
+}
+
+impl<T: Float> ArenaTree<T> {
+    /// Flattens the tree into a postfix program, visiting children exactly as _collapse does
+    /// Walks an explicit stack rather than recursing, so trees of any depth compile
+    pub fn compile(&self) -> Program<T> {
+        let mut instructions: Vec<Instruction<T>> = Vec::with_capacity(self.nodes.len());
+        // Nodes left to visit, and whether their operands were already compiled
+        let mut stack: Vec<(usize, bool)> = Vec::new();
+        if self.nodes.is_empty() {
+            instructions.push(Instruction::Number(T::ZERO));
+        } else {
+            stack.push((0, false));
+        }
+        while let Some((idx, expanded)) = stack.pop() {
+            let arena_node = self.nodes[idx];
+            let [left, right] = arena_node.children;
+            match arena_node.node {
+                Node::Number(n) => instructions.push(Instruction::Number(n)),
+                Node::Variable(i) => instructions.push(Instruction::Variable(i)),
+                op if expanded => {
+                    instructions.push(Instruction::Operator(op, left.is_some(), right.is_some()))
+                },
+                _ => {
+                    let operands = arena_node.children.iter().rev().flatten();
+                    stack.push((idx, true));
+                    stack.extend(operands.map(|&child| (child, false))); }
+            }
+        }
+        Program {instructions, context: Rc::clone(&self.context)}
+    }
+}
+
+impl<T: Float> Program<T> {
+    /// Evaluates the program at a single point
+    pub fn evaluate(&self, variables: &[T]) -> T {
+        let mut stack: Vec<T> = Vec::with_capacity(self.instructions.len());
+        for instruction in &self.instructions {
+            let value = match *instruction {
+                Instruction::Number(n) => n,
+                Instruction::Variable(i) => variables[i],
+                Instruction::Operator(op, has_left, has_right) => {
+                    let r = if has_right {T::MIN_POSITIVE + stack.pop().unwrap()}
+                            else {T::MIN_POSITIVE};
+                    let l = if has_left {T::MIN_POSITIVE + stack.pop().unwrap()}
+                            else {T::MIN_POSITIVE};
+                    self.context.primitives.apply(op, l, r) }
+            };
+            stack.push(value);
+        }
+        stack.pop().unwrap()
+    }
+
+    /// Evaluates the program over whole input columns, one per variable, of equal length
+    /// Each instruction runs once over the batch, with builtin operators in plain loops
+    /// that the compiler can vectorize
+    pub fn evaluate_batch(&self, columns: &[&[T]]) -> Vec<T> {
+        let mut out: Vec<T> = Vec::new();
+        self.evaluate_batch_into(columns, &mut out);
+        out
+    }
+
+    /// Like evaluate_batch, writing into out so its allocation can be reused
+    pub fn evaluate_batch_into(&self, columns: &[&[T]], out: &mut Vec<T>) {
+        let length = columns.first().map_or(0, |column| column.len());
+        assert!(columns.iter().all(|column| column.len() == length),
+                "Input columns must have the same length");
+        self.run(length, |i, column| column.extend_from_slice(columns[i]), out);
+    }
+
+    /// Evaluates the program at every point, gathering each variable column once
+    pub fn evaluate_points(&self, points: &[Point<T>]) -> Vec<T> {
+        let mut out: Vec<T> = Vec::new();
+        self.evaluate_points_into(points, &mut out);
+        out
+    }
+
+    pub fn evaluate_points_into(&self, points: &[Point<T>], out: &mut Vec<T>) {
+        self.run(points.len(), |i, column| column.extend(points.iter().map(|point| point.x[i])),
+                 out);
+    }
+
+    /// The stack machine over columns of length values
+    /// fill appends the column of a variable, buffers are borrowed from the context
+    fn run<F>(&self, length: usize, fill: F, out: &mut Vec<T>)
+            where F: Fn(usize, &mut Vec<T>) {
+        let mut spare = take(&mut *self.context.buffers.borrow_mut());
+        let mut stack: Vec<Vec<T>> = Vec::new();
+        let take_column = |spare: &mut Vec<Vec<T>>| {
+            let mut column = spare.pop().unwrap_or_default();
+            column.clear();
+            column
+        };
+        for instruction in &self.instructions {
+            let column = match *instruction {
+                Instruction::Number(n) => {
+                    let mut column = take_column(&mut spare);
+                    column.resize(length, n);
+                    column },
+                Instruction::Variable(i) => {
+                    let mut column = take_column(&mut spare);
+                    fill(i, &mut column);
+                    column },
+                Instruction::Operator(op, has_left, has_right) => {
+                    let right = if has_right {stack.pop()} else {None};
+                    let left = if has_left {stack.pop()} else {None};
+                    let mut padded = |operand: Option<Vec<T>>| match operand {
+                        Some(mut column) => {
+                            pad(&mut column);
+                            column },
+                        None => {
+                            let mut column = take_column(&mut spare);
+                            column.resize(length, T::MIN_POSITIVE);
+                            column }
+                    };
+                    let (mut l, r) = (padded(left), padded(right));
+                    self.apply(op, &mut l, &r);
+                    spare.push(r);
+                    l }
+            };
+            stack.push(column);
+        }
+        let result = stack.pop().unwrap();
+        out.clear();
+        out.extend_from_slice(&result);
+        spare.push(result);
+        *self.context.buffers.borrow_mut() = spare;
+    }
+
+    /// Applies an operator to padded operand columns, leaving the result in l
+    fn apply(&self, op: Node<T>, l: &mut [T], r: &[T]) {
+        let pairs = l.iter_mut().zip(r);
+        match op {
+            Node::Add => pairs.for_each(|(a, &b)| *a += b),
+            Node::Subtract => pairs.for_each(|(a, &b)| *a -= b),
+            Node::Multiply => pairs.for_each(|(a, &b)| *a *= b),
+            // Same as protected_divide
+            Node::Divide => pairs.for_each(|(a, &b)| {
+                *a = if b != T::ZERO {*a / b} else {*a / T::MIN_POSITIVE}
+            }),
+            Node::Min => pairs.for_each(|(a, &b)| *a = a.min(b)),
+            Node::Max => pairs.for_each(|(a, &b)| *a = a.max(b)),
+            Node::Abs => pairs.for_each(|(a, &b)| *a = (*a + b).abs()),
+            Node::Square => pairs.for_each(|(a, &b)| {
+                let x = *a + b;
+                *a = x * x
+            }),
+            Node::Negate => pairs.for_each(|(a, &b)| *a = -(*a + b)),
+            _ => match self.context.primitives.get(op).map(|primitive| &primitive.eval) {
+                Some(Eval::Unary(f)) => pairs.for_each(|(a, &b)| *a = f(*a + b)),
+                Some(Eval::Binary(f)) => pairs.for_each(|(a, &b)| *a = f(*a, b)),
+                None => pairs.for_each(|(a, _)| *a = T::ZERO)
+            }
+        }
+    }
+}
+
+/// Pads every value of a column as _collapse pads a present child
+fn pad<T: Float>(column: &mut [T]) {
+    for value in column.iter_mut() {
+        *value = T::MIN_POSITIVE + *value;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate rand;
+
+    use self::rand::{Rng, SeedableRng};
+    use self::rand::rngs::StdRng;
+    use super::*;
+    use super::super::PrimitiveSet;
+
+    /// Compiles spawned heaps over every builtin and checks each evaluation path against
+    /// collapse bit for bit, NaN included
+    fn matches_collapse<T: Float>() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for &variables in &[1, 3] {
+            let context = Rc::new(Context::<T>::new(variables, PrimitiveSet::all()));
+            let points: Vec<Point<T>> = (0..20).map(|_| {
+                let x = (0..variables).map(|_| T::from_f64(rng.gen_range(-3.0, 3.0))).collect();
+                Point::new(x, T::ZERO)
+            }).collect();
+            for _ in 0..300 {
+                let mut heap = SymbolicBinaryHeap::new(&context);
+                heap.spawn(&mut rng).unwrap();
+                let program = heap.compile();
+                let batch = heap.evaluate_points(&points);
+                for (point, &value) in points.iter().zip(&batch) {
+                    let expected = heap.collapse(&point.x);
+                    for actual in &[value, program.evaluate(&point.x)] {
+                        assert!(actual.to_f64().to_bits() == expected.to_f64().to_bits() ||
+                                    (actual.is_nan() && expected.is_nan()),
+                                "{} at {:?}: {} but collapse gives {}",
+                                heap, point.x, actual, expected);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn matches_collapse_f32() {
+        matches_collapse::<f32>();
+    }
+
+    #[test]
+    fn matches_collapse_f64() {
+        matches_collapse::<f64>();
+    }
+}
@@ -1,14 +1,26 @@
 extern crate rand;
 
 use std::fmt;
-use self::rand::{Rng, thread_rng};
+use std::mem::swap;
+use std::rc::Rc;
+use self::rand::Rng;
 
-pub const MAX_DEPTH: u32 = 6;
-pub const MAX_IDX: usize = length_from_depth(MAX_DEPTH);
-pub const MAX_NUMBER_NODE: f32 = 15.0;
-pub const MIN_NUMBER_NODE: f32 = -15.0;
+use super::{Context, Float, HeapError, BUILTIN_NAMES};
 
-#[derive(Debug, Clone, Copy)]
+/// How the heaps of an initial population are grown
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Initialization {
+    /// SymbolicBinaryHeap::spawn, layers filled by coin flips to a random depth
+    Random,
+    /// Operators down to a random depth, then terminals, see SymbolicBinaryHeap::random_full
+    Full,
+    /// Operators or terminals with equal odds below the root, see SymbolicBinaryHeap::random_grow
+    Grow,
+    /// Depths ramped from 2 to max_depth across the population, half full and half grown
+    RampedHalfAndHalf
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Node<T> {
     Add,
     Subtract,
@@ -16,29 +28,49 @@ pub enum Node<T> {
     Divide,
     Sine,
     Cosine,
-    Variable,
+    Exp,
+    Log,
+    Sqrt,
+    Pow,
+    Abs,
+    Tanh,
+    Square,
+    Negate,
+    Min,
+    Max,
+    Sign,
+    /// A user-registered operator, see PrimitiveSet::register_unary
+    Custom(usize),
+    Variable(usize),
     Number(T)
 }
 
-pub fn get_op() -> Option<Node<f32>> {
-    let node = match thread_rng().gen_range(0, 6) {
-        0 => Node::Add,
-        1 => Node::Subtract,
-        2 => Node::Multiply,
-        3 => Node::Divide,
-        4 => Node::Sine,
-        _ => Node::Cosine
-    };
-    Some(node)
-}
-
-pub fn get_val() -> Option<Node<f32>> {
-    let mut rng = thread_rng();
-    let terminal_node = match rng.gen_range(0, 2) {
-        0 => Node::Variable,
-        _ => Node::Number(rng.gen_range(MIN_NUMBER_NODE / 2.0, MAX_NUMBER_NODE / 2.0))
-    };
-    Some(terminal_node)
+impl<T> Node<T> {
+    /// Position of an operator in the primitive table, None for terminals
+    pub fn op_index(&self) -> Option<usize> {
+        let idx = match self {
+            Node::Add => 0,
+            Node::Subtract => 1,
+            Node::Multiply => 2,
+            Node::Divide => 3,
+            Node::Sine => 4,
+            Node::Cosine => 5,
+            Node::Exp => 6,
+            Node::Log => 7,
+            Node::Sqrt => 8,
+            Node::Pow => 9,
+            Node::Abs => 10,
+            Node::Tanh => 11,
+            Node::Square => 12,
+            Node::Negate => 13,
+            Node::Min => 14,
+            Node::Max => 15,
+            Node::Sign => 16,
+            Node::Custom(i) => BUILTIN_NAMES.len() + i,
+            Node::Variable(_) | Node::Number(_) => return None
+        };
+        Some(idx)
+    }
 }
 
 pub const fn length_from_depth(depth: u32) -> usize {
@@ -80,15 +112,86 @@ pub fn check_related(idx1: usize, idx2: usize) -> bool {
 #[derive(Debug, Clone)]
 pub struct SymbolicBinaryHeap<T> {
     pub heap: Vec<Option<Node<T>>>,
-    pub rng: rand::ThreadRng
+    pub context: Rc<Context<T>>
+}
+
+/// Binding strength of printed subexpressions, from loosest to tightest
+const PREC_SUM: u8 = 1;
+const PREC_PRODUCT: u8 = 2;
+const PREC_NEGATION: u8 = 3;
+const PREC_POWER: u8 = 4;
+const PREC_ATOM: u8 = 5;
+
+/// A constant in the fewest digits that read back exactly, switching to scientific notation
+/// below 1e-4 and from 1e16 on, as Python does, rather than writing out hundreds of zeros
+pub(crate) fn format_number<T: Float>(n: T) -> String {
+    let scientific = format!("{:e}", n);
+    let exponent: i32 = match scientific.split_once('e') {
+        Some((_, exponent)) => exponent.parse().unwrap_or(0),
+        None => 0 // Infinite or NaN
+    };
+    if !(-4..16).contains(&exponent) {scientific} else {format!("{}", n)}
 }
 
-/// Prints each level on a new line
-impl fmt::Display for SymbolicBinaryHeap<f32> {
+/// Infix string of the subtree at idx, with its binding strength
+/// node_at returns the node at an index along with the indices of its operands
+/// Operands are parenthesized only when they bind looser than their operator requires
+pub(crate) fn infix<T: Float, I: Copy, F>(context: &Context<T>, node_at: &F, idx: I)
+                                                                          -> (String, u8)
+        where F: Fn(I) -> Option<(Node<T>, I, I)> {
+    let (node, left_idx, right_idx) = match node_at(idx) {
+        Some(found) => found,
+        None => return ("?".to_string(), PREC_ATOM) // Missing operand
+    };
+    let operand = |child_idx: I, min_prec: u8| {
+        let (s, prec) = infix(context, node_at, child_idx);
+        if prec < min_prec {format!("({})", s)} else {s}
+    };
+    let infix_op = |symbol: &str, prec: u8| {
+        (format!("{} {} {}", operand(left_idx, prec), symbol, operand(right_idx, prec + 1)),
+         prec)
+    };
+    match node {
+        Node::Number(n) if n < T::ZERO => (format_number(n), PREC_NEGATION),
+        Node::Number(n) => (format_number(n), PREC_ATOM),
+        Node::Variable(i) => (context.variable_name(i), PREC_ATOM),
+        Node::Add => infix_op("+", PREC_SUM),
+        Node::Subtract => infix_op("-", PREC_SUM),
+        Node::Multiply => infix_op("*", PREC_PRODUCT),
+        Node::Divide => infix_op("/", PREC_PRODUCT),
+        Node::Pow => { // Right associative
+            let (l, r) = (operand(left_idx, PREC_POWER + 1), operand(right_idx, PREC_POWER));
+            (format!("{} ^ {}", l, r), PREC_POWER) },
+        Node::Negate => match node_at(left_idx) { // -(2) so it isn't read as the literal -2
+            Some((Node::Number(n), _, _)) => (format!("-({})", format_number(n)), PREC_NEGATION),
+            _ => (format!("-{}", operand(left_idx, PREC_NEGATION)), PREC_NEGATION)
+        },
+        op => {
+            let name = context.primitives.get(op).map_or("?", |p| p.name.as_str());
+            let args = if context.primitives.arity(op) == 2 {
+                format!("{}, {}", operand(left_idx, 0), operand(right_idx, 0))
+            } else {
+                operand(left_idx, 0)
+            };
+            (format!("{}({})", name, args), PREC_ATOM)
+        }
+    }
+}
+
+/// Prints the expression in infix notation, such as (x + 1.93) * sin(x)
+/// The alternate flag ({:#}) prints each level of the heap on a new line instead
+impl<T: Float> fmt::Display for SymbolicBinaryHeap<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !f.alternate() {
+            let node_at = |idx: usize| match self.heap.get(idx) {
+                Some(Some(node)) => Some((*node, 2 * idx + 1, 2 * idx + 2)),
+                _ => None
+            };
+            return write!(f, "{}", infix(&self.context, &node_at, 0).0);
+        }
         let mut msg = String::new();
-        for i in 0..MAX_DEPTH {
-            let data: &[Option<Node<f32>>] =
+        for i in 0..self.max_depth() {
+            let data: &[Option<Node<T>>] =
                 &self.heap[length_from_depth(i)..length_from_depth(i + 1)];
             msg.push_str(&format!("{data:?}\n").to_string())
         }
@@ -96,203 +199,393 @@ impl fmt::Display for SymbolicBinaryHeap<f32> {
     }
 }
 
-impl SymbolicBinaryHeap<f32> {
-    /// Default constructor, creates an empty heap
-    pub fn new() -> Self {
-        let heap: Vec<Option<Node<f32>>> = vec![None; MAX_IDX + 1];
-        SymbolicBinaryHeap {heap, rng: thread_rng() }
+impl<T: Float> SymbolicBinaryHeap<T> {
+    /// Default constructor, creates an empty heap sharing the simulation's context
+    pub fn new(context: &Rc<Context<T>>) -> Self {
+        let heap: Vec<Option<Node<T>>> = vec![None; length_from_depth(context.max_depth) + 1];
+        SymbolicBinaryHeap {heap, context: Rc::clone(context)}
     }
 
     /// Construct a heap using a premade Some(Node) vector
-    pub fn new_from(heap: Vec<Option<Node<f32>>>) -> Self {
-        let heap: Vec<Option<Node<f32>>> = heap;
-        SymbolicBinaryHeap {heap, rng: thread_rng()}
+    /// The vector is padded with None up to the context's maximum depth
+    /// Fails if it holds a node beyond the slots of max_depth
+    pub fn new_from(heap: Vec<Option<Node<T>>>, context: &Rc<Context<T>>)
+                                                         -> Result<Self, HeapError> {
+        let mut heap: Vec<Option<Node<T>>> = heap;
+        let length = length_from_depth(context.max_depth) + 1;
+        if let Some(idx) = heap.iter().skip(length).position(|node| node.is_some()) {
+            return Err(HeapError::TooDeep(length + idx));
+        }
+        heap.resize(length, None);
+        Ok(SymbolicBinaryHeap {heap, context: Rc::clone(context)})
+    }
+
+    /// Deepest level a node may be placed at
+    pub fn max_depth(&self) -> u32 {
+        self.context.max_depth
+    }
+
+    /// Bound on child indices, the heap holds max_idx + 1 slots
+    pub fn max_idx(&self) -> usize {
+        length_from_depth(self.context.max_depth)
+    }
+
+    /// Returns a random enabled operation node
+    pub fn get_op<R: Rng>(&self, rng: &mut R) -> Option<Node<T>> {
+        Some(self.context.random_op(rng))
+    }
+
+    /// Returns a random enabled operation node taking arity children, if there is one
+    pub fn get_op_with_arity<R: Rng>(&self, arity: usize, rng: &mut R) -> Option<Node<T>> {
+        self.context.random_op_with_arity(arity, rng)
+    }
+
+    /// Number of children the node at idx takes
+    pub fn arity(&self, idx: usize) -> usize {
+        self.heap[idx].map_or(0, |node| self.context.primitives.arity(node))
+    }
+
+    /// Returns a random terminal node, sampling among the available variables
+    pub fn get_val<R: Rng>(&self, rng: &mut R) -> Option<Node<T>> {
+        Some(self.context.random_terminal(rng))
     }
 
     // Performs random_instantiate on an empty heap
-    pub fn spawn(&mut self) {
-        let depth: u32 = self.rng.gen_range(2, MAX_DEPTH);
-        self.random_instantiate(0, depth);
+    pub fn spawn<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        if self.max_depth() < 3 {
+            return Err(HeapError::BadDepth {idx: 0, depth: self.max_depth()});
+        }
+        let depth: u32 = rng.gen_range(2, self.max_depth());
+        self.random_instantiate(0, depth, rng)
     }
     
-    /// Returns the left child node (the only child of Sine/Cosine nodes)
-    pub fn left(&mut self, base_idx: usize) -> &mut Option<Node<f32>> {
+    /// Returns the left child node (the only child of unary nodes)
+    /// Fails if it would lie below max_depth
+    pub fn left(&mut self, base_idx: usize) -> Result<&mut Option<Node<T>>, HeapError> {
         let left_idx = 2 * base_idx + 1;
-        if left_idx > MAX_IDX {
-            panic!("Attempted to find child at {}, below MAX_DEPTH.\nself:\n{}", left_idx, self)
+        if left_idx > self.max_idx() {
+            return Err(HeapError::BadIndex(base_idx));
         }
-        &mut self.heap[left_idx]
+        Ok(&mut self.heap[left_idx])
     }
     
-    /// Returns the right child node (the only child of Sine/Cosine nodes)
-    pub fn right(&mut self, base_idx: usize) -> &mut Option<Node<f32>> {
+    /// Returns the right child node, always None below unary nodes, whose operand is on the left
+    /// Fails if it would lie below max_depth
+    pub fn right(&mut self, base_idx: usize) -> Result<&mut Option<Node<T>>, HeapError> {
         let right_idx = 2 * base_idx + 2;
-        if right_idx > MAX_IDX {
-            panic!("Attempted to find child at {}, below MAX_DEPTH.\nself:\n{}", right_idx, self)
+        if right_idx > self.max_idx() {
+            return Err(HeapError::BadIndex(base_idx));
         }
-        &mut self.heap[right_idx]
+        Ok(&mut self.heap[right_idx])
     }
     
-    /// Returns the parent node
-    pub fn parent(&mut self, idx: usize) -> &mut Option<Node<f32>> {
-        if idx < 1 {
-            panic!("Attempted to find parent at invalid index {}.\nself:\n{}", idx, self)
+    /// Returns the parent node, failing for the root or an index past the heap
+    pub fn parent(&mut self, idx: usize) -> Result<&mut Option<Node<T>>, HeapError> {
+        if idx < 1 || idx > self.max_idx() {
+            return Err(HeapError::BadIndex(idx));
         }
-        &mut self.heap[(idx - 1) / 2]
+        Ok(&mut self.heap[(idx - 1) / 2])
     }
 
-    pub fn has_variable(&mut self) -> bool {
-        let mut has_variable = false;
-        for node in self.heap.iter() {
-            match node {
-                Some(Node::Variable) => {
-                    has_variable = true; 
-                    break; },
-                _ => ()
-            }
+    pub fn has_variable(&self) -> bool {
+        self.heap.iter().any(|node| matches!(node, Some(Node::Variable(_))))
+    }
+
+    /// Turns a random constant into a variable if the heap has no variable
+    /// Returns whether anything changed
+    pub fn ensure_variable<R: Rng>(&mut self, rng: &mut R) -> bool {
+        if self.has_variable() {
+            return false;
         }
-        has_variable
+        let constants: Vec<usize> = (0..self.heap.len())
+            .filter(|&i| matches!(self.heap[i], Some(Node::Number(_))))
+            .collect();
+        if constants.is_empty() {
+            return false;
+        }
+        let choice = constants[rng.gen_range(0, constants.len())];
+        self.heap[choice] = Some(Node::Variable(rng.gen_range(0, self.context.variables)));
+        true
     }
 
     /// Recursively delete child nodes
     fn _delete_from_idx(&mut self, idx: usize) {
         let left_idx = 2 * idx + 1;
         let right_idx = left_idx + 1;
-        if left_idx < MAX_IDX {
+        if left_idx < self.max_idx() {
             self.heap[left_idx] = None;
             self._delete_from_idx(left_idx)
         }
-        if right_idx <= MAX_IDX {
+        if right_idx <= self.max_idx() {
             self.heap[right_idx] = None;
             self._delete_from_idx(right_idx)
         }
     }
 
     /// Construct a node for idx with a variable or a constant of the node solved at 1
-    fn op_to_terminal(&mut self, idx: usize) -> Node<f32> {
-        let use_variable: bool = self.rng.gen();
+    /// The constant is kept in range, see Context::bound_constant
+    fn op_to_terminal<R: Rng>(&mut self, idx: usize, rng: &mut R) -> Node<T> {
+        let use_variable: bool = rng.gen();
         if use_variable {
-            Node::Variable
+            Node::Variable(rng.gen_range(0, self.context.variables))
         } else {
-            let num: f32 = self._collapse(idx, 1.0);
-            Node::Number(num)
+            let num: T = self._collapse(idx, &vec![T::ONE; self.context.variables]);
+            Node::Number(self.context.bound_constant(num, rng))
         }
     }
 
     /// Replace the node at idx op_to_terminal
     /// Then recursively delete any potential child nodes
-    pub fn delete_from_idx(&mut self, idx: usize) {
+    pub fn delete_from_idx<R: Rng>(&mut self, idx: usize, rng: &mut R) -> Result<(), HeapError> {
+        if idx == 0 || idx > self.max_idx() {
+            return Err(HeapError::BadIndex(idx));
+        }
         let parent_idx = (idx - 1) / 2;
         // Defines a mapping between parent node and number of children
-        self.heap[idx] = match self.heap[parent_idx] {
-            Some(Node::Sine) | Some(Node::Cosine) =>
-                if parent_idx == idx / 2 { // if idx is a left child node
-                    Some(self.op_to_terminal(idx))
+        self.heap[idx] = match self.arity(parent_idx) {
+            1 => if parent_idx == idx / 2 { // if idx is a left child node
+                    Some(self.op_to_terminal(idx, rng))
                 } else {None},
-            Some(Node::Add) | Some(Node::Subtract) |
-            Some(Node::Multiply) | Some(Node::Divide) =>
-                Some(self.op_to_terminal(idx)),
+            2 => Some(self.op_to_terminal(idx, rng)),
             _ => None // includes Node::Variable and Node::Number
         };
-        self._delete_from_idx(idx)
+        self._delete_from_idx(idx);
+        Ok(())
     }
 
     /// Adds self.heap[idx] to heap then recurses into children if possible
-    fn _heap_at_idx(&mut self, idx: usize, self_idx: usize, heap: &mut Vec<Option<Node<f32>>>){
-        heap[idx] = self.heap[self_idx].clone();
+    fn _heap_at_idx(&mut self, idx: usize, self_idx: usize, heap: &mut Vec<Option<Node<T>>>){
+        heap[idx] = self.heap[self_idx];
         let left_idx = 2 * idx + 1;
         let right_idx = 2 * idx + 2;
         let left_self_idx = 2 * self_idx + 1;
         let right_self_idx = 2 * self_idx + 2;
         // self_idx >= idx always since idx starts at 0
-        if left_self_idx <= MAX_IDX {
+        if left_self_idx <= self.max_idx() {
             self._heap_at_idx(left_idx, left_self_idx, heap);
         }
-        if right_self_idx <= MAX_IDX {
+        if right_self_idx <= self.max_idx() {
             self._heap_at_idx(right_idx, right_self_idx, heap);
         }
     }
 
     /// Returns a new heap built starting from the idx of this object
     pub fn heap_at_idx(&mut self, base_idx: usize) -> Self {
-        let mut heap: Vec<Option<Node<f32>>> = vec![None; MAX_IDX + 1];
+        let mut heap: Vec<Option<Node<T>>> = vec![None; self.max_idx() + 1];
         self._heap_at_idx(0, base_idx, &mut heap);
-        SymbolicBinaryHeap::new_from(heap)
+        SymbolicBinaryHeap {heap, context: Rc::clone(&self.context)}
     }
     
     /// Determines how many children a node can take and applies them
     /// Helper function for random SymbolicBinaryHeap generation
-    fn fill_node(&mut self, idx: usize, getter1: fn() -> Option<Node<f32>>,
-                                        getter2: fn() -> Option<Node<f32>>) {
-        assert!(idx <= (MAX_IDX - 1) / 2,
-            "Attempted to fill node at invalid index {}.\nself:\n{}", idx, self);
+    /// Fails if the children would lie below max_depth
+    fn fill_node<R: Rng>(&mut self, idx: usize, getter1: fn(&Self, &mut R) -> Option<Node<T>>,
+                         getter2: fn(&Self, &mut R) -> Option<Node<T>>, rng: &mut R)
+                         -> Result<(), HeapError> {
         // Defines a mapping between parent node and number of children
-        match self.heap[idx] {
-            Some(Node::Add) | Some(Node::Subtract) |
-            Some(Node::Multiply) | Some(Node::Divide) => {
-                *self.left(idx) = getter1();
-                *self.right(idx) = getter2() },
-            Some(Node::Sine) | Some(Node::Cosine) => {
-                *self.left(idx) = getter1();
-                *self.right(idx) = None; },
-            _ => { // Includes Variable and Number(_)
-                *self.left(idx) = None;
-                *self.right(idx) = None; }
+        let (left, right) = match self.arity(idx) {
+            2 => (getter1(self, rng), getter2(self, rng)),
+            1 => (getter1(self, rng), None),
+            _ => (None, None) // Includes Variable and Number(_)
         };
+        *self.left(idx)? = left;
+        *self.right(idx)? = right;
+        Ok(())
     }
 
     /// Generates a SymbolicBinaryHeap of a given depth
     /// A new heap should be instantiated at base_idx = 0
     /// A non-zero base_idx is used as a potential mutation
-    pub fn random_instantiate(&mut self, base_idx: usize, depth: u32) {
+    /// Fails if a subtree of that depth doesn't fit at base_idx
+    pub fn random_instantiate<R: Rng>(&mut self, base_idx: usize, depth: u32, rng: &mut R)
+                                      -> Result<(), HeapError> {
+        if depth == 0 || (base_idx == 0 && depth == 1) ||
+                depth_from_idx(base_idx) + depth - 1 > self.max_depth() {
+            return Err(HeapError::BadDepth {idx: base_idx, depth});
+        }
         self._delete_from_idx(base_idx);
-        assert!(depth_from_idx(base_idx) + depth - 1 <= MAX_DEPTH,
-            "Attempted to random_instantiate from index {} with depth {}.\nself:\n{}",
-            base_idx, depth, self);
         if base_idx == 0 {
-            if depth > 1 {
-                self.heap[base_idx] = get_op();
-                // Randomly fill all possible operation nodes
-                for layer in 0..(depth - 2) {
-                    let nodes_in_layer = 2_usize.pow(layer);
-                    for i in 0..nodes_in_layer {
-                        let idx = base_idx + layer as usize + i;
-                        let use_op: bool = self.rng.gen();
-                        assert!(depth_from_idx(idx) < MAX_DEPTH - 1,
-                            "Tried to place an operation at the lowest depth.\nself:\n{}",
-                            self);
-                        let getter: fn() -> Option<Node<f32>> =
-                            if use_op {get_op} else {get_val};
-                        if self.rng.gen() {
-                            self.fill_node(idx, get_op, getter);
-                        } else {
-                            self.fill_node(idx, getter, get_op);
-                        }
-                    }
-                }
-                // Index into the last layer created (or 0) and add terminals
-                let nodes_in_layer = 2_usize.pow(depth - 2);
-                let offset = base_idx + nodes_in_layer - 1;
+            self.heap[base_idx] = self.get_op(rng);
+            // Randomly fill all possible operation nodes
+            for layer in 0..(depth - 2) {
+                let nodes_in_layer = 2_usize.pow(layer);
                 for i in 0..nodes_in_layer {
-                    self.fill_node(offset + i, get_val, get_val);
+                    let idx = base_idx + nodes_in_layer - 1 + i;
+                    let use_op: bool = rng.gen();
+                    debug_assert!(depth_from_idx(idx) < self.max_depth() - 1,
+                        "Tried to place an operation at the lowest depth.\nself:\n{}", self);
+                    let getter: fn(&Self, &mut R) -> Option<Node<T>> =
+                        if use_op {Self::get_op} else {Self::get_val};
+                    if rng.gen() {
+                        self.fill_node(idx, Self::get_op, getter, rng)?;
+                    } else {
+                        self.fill_node(idx, getter, Self::get_op, rng)?;
+                    }
                 }
-            } else {
-                panic!("Tried to instantiate at 0 with bad depth {}.\nself:\n{}\n",
-                       depth, self);
             }
+            // Index into the last layer created (or 0) and add terminals
+            let nodes_in_layer = 2_usize.pow(depth - 2);
+            let offset = base_idx + nodes_in_layer - 1;
+            for i in 0..nodes_in_layer {
+                self.fill_node(offset + i, Self::get_val, Self::get_val, rng)?;
+            }
+        } else if depth > 1 {
+            let mut source = SymbolicBinaryHeap::<T>::new(&self.context);
+            source.random_instantiate(0, depth, rng)?;
+            self._delete_from_idx(base_idx);
+            self._swap_from(Some(source), base_idx, 0);
         } else {
-            if depth > 1 {
-                let mut source = SymbolicBinaryHeap::<f32>::new();
-                source.random_instantiate(0, depth);
-                self._delete_from_idx(base_idx);
-                self._swap_from(Some(source), base_idx, 0);    
-            } else if depth == 1 {
-                self.heap[base_idx] = get_val();
-            } else {
-                panic!("Tried to instantiate at {} with bad depth {}.\nself:\n{}\n",
-                       base_idx, depth, self);
+            self.heap[base_idx] = self.get_val(rng);
+        }
+        Ok(())
+    }
+
+    /// Replaces the heap with one where every branch reaches depth, operators filling each
+    /// level above the last and terminals the last, fails unless 2 <= depth <= max_depth
+    pub fn random_full<R: Rng>(&mut self, depth: u32, rng: &mut R) -> Result<(), HeapError> {
+        self.random_fill(depth, true, rng)
+    }
+
+    /// Replaces the heap with one of at most depth levels, with an operator at the root and
+    /// operators or terminals with equal odds below, fails unless 2 <= depth <= max_depth
+    pub fn random_grow<R: Rng>(&mut self, depth: u32, rng: &mut R) -> Result<(), HeapError> {
+        self.random_fill(depth, false, rng)
+    }
+
+    fn random_fill<R: Rng>(&mut self, depth: u32, full: bool, rng: &mut R)
+                                                          -> Result<(), HeapError> {
+        if depth < 2 || depth > self.max_depth() {
+            return Err(HeapError::BadDepth {idx: 0, depth});
+        }
+        self.heap[0] = None;
+        self._delete_from_idx(0);
+        self._random_fill(0, depth, full, rng);
+        Ok(())
+    }
+
+    fn _random_fill<R: Rng>(&mut self, idx: usize, depth: u32, full: bool, rng: &mut R) {
+        let use_op = depth > 1 && (idx == 0 || full || rng.gen());
+        self.heap[idx] = if use_op {self.get_op(rng)} else {self.get_val(rng)};
+        let left_idx = 2 * idx + 1;
+        match self.arity(idx) {
+            2 => {
+                self._random_fill(left_idx, depth - 1, full, rng);
+                self._random_fill(left_idx + 1, depth - 1, full, rng); },
+            1 => self._random_fill(left_idx, depth - 1, full, rng),
+            _ => ()
+        }
+    }
+
+    /// Name of a variable as printed and parsed: x, or x0, x1, ... with several inputs
+    pub fn variable_name(&self, i: usize) -> String {
+        self.context.variable_name(i)
+    }
+
+    /// Restores a valid layout after branches were moved or truncated
+    /// Operators get all of their operands, and nothing is left below terminals or empty slots
+    /// Returns whether anything changed
+    pub fn repair<R: Rng>(&mut self, rng: &mut R) -> Result<bool, HeapError> {
+        if self.heap[0].is_none() {
+            self.random_instantiate(0, 2, rng)?;
+            return Ok(true);
+        }
+        Ok(self._repair(0, rng))
+    }
+
+    fn _repair<R: Rng>(&mut self, idx: usize, rng: &mut R) -> bool {
+        let left_idx = 2 * idx + 1;
+        let right_idx = left_idx + 1;
+        if right_idx >= self.max_idx() { // Deepest level, no room for operands
+            let mut changed = false;
+            if left_idx <= self.max_idx() && self.heap[left_idx].is_some() { // The spare slot
+                self.heap[left_idx] = None;
+                changed = true;
+            }
+            if self.arity(idx) > 0 {
+                self.heap[idx] = Some(self.op_to_terminal(idx, rng));
+                changed = true;
+            }
+            return changed;
+        }
+        let mut changed = false;
+        let arity = self.arity(idx);
+        if arity == 1 && self.heap[left_idx].is_none() && self.heap[right_idx].is_some() {
+            self._swap_from(None, left_idx, right_idx); // Unary operands go on the left
+            changed = true;
+        }
+        for (child_idx, needed) in [(left_idx, arity > 0), (right_idx, arity > 1)] {
+            if needed {
+                if self.heap[child_idx].is_none() {
+                    self.heap[child_idx] = self.get_val(rng);
+                    changed = true;
+                }
+                changed |= self._repair(child_idx, rng);
+            } else if self.heap[child_idx].is_some() || self.heap_below(child_idx) {
+                self.heap[child_idx] = None;
+                self._delete_from_idx(child_idx);
+                changed = true;
             }
         }
+        changed
+    }
+
+    /// Checks the invariants repair restores, and that every node belongs to the context
+    /// Operators have exactly their operands, unary ones on the left, nothing hangs below
+    /// a terminal or an empty slot, and nothing lies deeper than max_depth
+    pub fn validate(&self) -> Result<(), HeapError> {
+        if self.heap[0].is_none() {
+            return Err(HeapError::Empty);
+        }
+        for (idx, node) in self.heap.iter().enumerate() {
+            let node = match node {
+                Some(node) => *node,
+                None => continue
+            };
+            if depth_from_idx(idx) > self.max_depth() {
+                return Err(HeapError::TooDeep(idx));
+            }
+            if idx > 0 {
+                let parent_arity = self.arity((idx - 1) / 2);
+                let is_left = idx % 2 == 1;
+                if parent_arity == 0 || (parent_arity == 1 && !is_left) {
+                    return Err(HeapError::Orphan(idx));
+                }
+            }
+            match node {
+                Node::Number(_) => (),
+                Node::Variable(i) => if i >= self.context.variables {
+                    return Err(HeapError::VariableOutOfRange(idx));
+                },
+                op => {
+                    if self.context.primitives.get(op).is_none() {
+                        return Err(HeapError::UnknownOperator(idx));
+                    }
+                    let left_idx = 2 * idx + 1;
+                    let operands = [(left_idx, left_idx < self.max_idx()),
+                                    (left_idx + 1, left_idx < self.max_idx())];
+                    for &(child_idx, fits) in operands.iter().take(self.arity(idx)) {
+                        if !fits || self.heap[child_idx].is_none() {
+                            return Err(HeapError::MissingOperand(idx));
+                        }
+                    } }
+            };
+        }
+        Ok(())
+    }
+
+    /// Returns whether any node hangs below idx
+    fn heap_below(&self, idx: usize) -> bool {
+        let mut idxs: Vec<usize> = vec![2 * idx + 1, 2 * idx + 2];
+        while let Some(idx) = idxs.pop() {
+            if idx < self.max_idx() {
+                if self.heap[idx].is_some() {
+                    return true;
+                }
+                idxs.push(2 * idx + 1);
+                idxs.push(2 * idx + 2);
+            }
+        }
+        false
     }
 
     pub fn _swap_from(&mut self, ext_src: Option<Self>, choice1: usize, choice2: usize) {
@@ -310,17 +603,17 @@ impl SymbolicBinaryHeap<f32> {
             let idx1_right = 2 * idx1 + 2;
             let idx2_left = 2 * idx2 + 1;
             let idx2_right = 2 * idx2 + 2;
-            if idx1_left < MAX_IDX && idx2_left < MAX_IDX {
+            if idx1_left < self.max_idx() && idx2_left < self.max_idx() {
                 swap_idxs.push((idx1_left, idx2_left));
             }
-            if idx1_right <= MAX_IDX && idx2_right <= MAX_IDX {
+            if idx1_right <= self.max_idx() && idx2_right <= self.max_idx() {
                 swap_idxs.push((idx1_right, idx2_right));
             }
         }
     }
 
     /// Here, complexity is defined as the number of nodes in the heap
-    pub fn complexity(&mut self) -> u32 {
+    pub fn complexity(&self) -> u32 {
         let mut complexity: u32 = 0;
         for node in &self.heap {
             if node.is_some() {
@@ -331,119 +624,147 @@ impl SymbolicBinaryHeap<f32> {
     }
 
     /// Returns the depth of the deepest node in the binary heap
-    /// Assumes each operation node has at least one terminal node below it
-    pub fn depth(&mut self) -> u32 {
-        depth_from_idx(*self.get_terminal_idxs().last().unwrap_or( {
-            self.random_instantiate(0, 2);
-            &2}))
+    /// Assumes each operation node has at least one terminal node below it,
+    /// otherwise the deepest node of any kind counts, and an empty heap has depth 0
+    pub fn depth(&self) -> u32 {
+        let deepest = match self.get_terminal_idxs().last() {
+            Some(&idx) => Some(idx),
+            None => self.heap.iter().rposition(|node| node.is_some())
+        };
+        deepest.map_or(0, depth_from_idx)
     }
 
     /// Performs self.get_terminal_idxs() and checks if empty
-    fn get_checked_terminals(&mut self) -> Vec<usize> {
+    fn get_checked_terminals<R: Rng>(&mut self, rng: &mut R) -> Result<Vec<usize>, HeapError> {
         let mut node_idxs = self.get_terminal_idxs();
-        if node_idxs.len() == 0 { // No terminal nodes
+        if node_idxs.is_empty() { // No terminal nodes
             node_idxs = self.get_op_idxs();
             if node_idxs.len() <= 1 { // And only the top operation node
-                let depth = self.rng.gen_range(2, 4);
-                self.random_instantiate(0, depth);
+                let depth = rng.gen_range(2, 4);
+                self.random_instantiate(0, depth, rng)?;
             } else if node_idxs.len() > 1 { // Multiple operators present
-                let choice = node_idxs[self.rng.gen_range(1, node_idxs.len())];
-                self.delete_from_idx(choice);
+                let choice = node_idxs[rng.gen_range(1, node_idxs.len())];
+                self.delete_from_idx(choice, rng)?;
             }
             node_idxs = self.get_terminal_idxs();
         }
         if self.heap[1].is_some() {
             for _ in 0..node_idxs.len() {
-                if self.parent(*node_idxs.last().unwrap()).is_none() {
+                if self.parent(*node_idxs.last().unwrap())?.is_none() {
                     self._delete_from_idx(node_idxs.pop().unwrap());
                 }
             }
         }
-        node_idxs
+        if node_idxs.is_empty() {
+            return Err(HeapError::NoCandidates);
+        }
+        Ok(node_idxs)
     }
 
     /// Returns the index in the heap of a randomized parent node for swapping
-    pub fn get_swap_idx(&mut self) -> usize {
+    pub fn get_swap_idx<R: Rng>(&mut self, rng: &mut R) -> Result<usize, HeapError> {
+        // Makes sure there are terminals to fall back on
+        let terminal_idxs = self.get_checked_terminals(rng)?;
         let depth = self.depth();
-        let target_depth = self.rng.gen_range(1, depth);
-        let mut node_idxs: Vec<usize>;
+        if depth < 2 { // A lone terminal, with nothing below the root to swap
+            return Err(HeapError::NoCandidates);
+        }
+        let target_depth = rng.gen_range(1, depth);
         if target_depth > 1 {
-            node_idxs = self.get_op_idxs();
+            let mut node_idxs = self.get_op_idxs();
             let mut choice: usize;
             for _ in 0..node_idxs.len() {
-                choice = node_idxs.swap_remove(self.rng.gen_range(0, node_idxs.len()));
+                choice = node_idxs.swap_remove(rng.gen_range(0, node_idxs.len()));
                 if self.heap_at_idx(choice).depth() == target_depth {
-                    return choice;
+                    return Ok(choice);
                 }
             }
         }
-        node_idxs = self.get_checked_terminals();
-        node_idxs[self.rng.gen_range(0, node_idxs.len())]
+        Ok(terminal_idxs[rng.gen_range(0, terminal_idxs.len())])
     }
 
-    pub fn check_swap_idx(&mut self, idx: usize) {
-        assert!(self.heap[idx].is_some(), "Bad swap index {}.\nself:\n{}\n", idx, self);
+    /// Prepares the parent of idx to take whatever branch is swapped in at idx
+    pub fn check_swap_idx<R: Rng>(&mut self, idx: usize, rng: &mut R) -> Result<(), HeapError> {
+        if idx == 0 || idx > self.max_idx() || self.heap[idx].is_none() {
+            return Err(HeapError::BadIndex(idx));
+        }
         let parent_idx = (idx - 1) / 2;
-        match self.parent(idx) {
-            Some(Node::Sine) | Some(Node::Cosine) =>
-                if parent_idx == idx / 2 {
-                    if self.right(parent_idx).is_some() {
+        match self.arity(parent_idx) {
+            1 => if parent_idx == idx / 2 {
+                    if self.right(parent_idx)?.is_some() {
                         self._delete_from_idx(idx + 1);
                         self.heap[idx + 1] = None;
                     }
-                } else if self.left(parent_idx).is_some() {
+                } else if self.left(parent_idx)?.is_some() {
                     self._delete_from_idx(idx - 1);
                     self.heap[idx - 1] = None;
                 },
-            Some(Node::Add) | Some(Node::Subtract) |
-            Some(Node::Multiply) | Some(Node::Divide) => (),
+            2 => (),
             _ => { // includes Node::Variable and Node::Number
-                let node: Option<Node<f32>>;
-                if parent_idx == idx / 2 && idx + 1 <= MAX_IDX {
-                    if self.right(parent_idx).is_some() {
-                        node = match self.rng.gen_range(0, 4) {
-                            0 => Some(Node::Add),
-                            1 => Some(Node::Subtract),
-                            2 => Some(Node::Multiply),
-                            _ => Some(Node::Divide)
-                        };
-                    } else {
-                        node = match self.rng.gen_range(0, 2) {
-                            0 => Some(Node::Sine),
-                            _ => Some(Node::Cosine)
-                        };
-                    }
-                } else {
-                    if self.left(parent_idx).is_none() {
-                        match self.parent(parent_idx) {
-                            Some(Node::Sine) | Some(Node::Cosine) => (),
-                            Some(Node::Variable) | Some(Node::Number(_)) | None =>
-                                panic!("Lost terminal node.\nself:\n{}\n", self),
-                            _ => *self.left(parent_idx) = get_val()
-                        };
+                let mut node: Option<Node<T>> = None;
+                if parent_idx == idx / 2 && idx < self.max_idx() {
+                    if self.right(parent_idx)?.is_none() {
+                        node = self.get_op_with_arity(1, rng);
+                        if node.is_none() { // No unary operators, so give it a sibling
+                            *self.right(parent_idx)? = self.get_val(rng);
+                        }
                     }
-                    node = get_op();
+                } else if self.left(parent_idx)?.is_none() {
+                    match self.arity((parent_idx - 1) / 2) {
+                        1 => (),
+                        0 => return Err(HeapError::Orphan(parent_idx)),
+                        _ => *self.left(parent_idx)? = self.get_val(rng)
+                    };
                 }
-                *self.parent(idx) = node; }
+                if node.is_none() {
+                    node = self.get_op_with_arity(2, rng);
+                }
+                *self.parent(idx)? = node; }
         };
+        Ok(())
+    }
+
+    /// Swaps a random branch of this heap with one of other, chosen by get_swap_idx
+    /// Both heaps are repaired afterwards, and fail if that doesn't make them valid
+    pub fn cross_over<R: Rng>(&mut self, other: &mut Self, rng: &mut R) -> Result<(), HeapError> {
+        let mut swap_idxs: Vec<(usize, usize)> = Vec::new();
+        swap_idxs.push((self.get_swap_idx(rng)?, other.get_swap_idx(rng)?));
+        self.check_swap_idx(swap_idxs[0].0, rng)?;
+        other.check_swap_idx(swap_idxs[0].1, rng)?;
+        let max_idx = self.max_idx();
+        while let Some((idx, other_idx)) = swap_idxs.pop() {
+            swap(&mut self.heap[idx], &mut other.heap[other_idx]);
+            let (left_idx, right_idx) = (2 * idx + 1, 2 * idx + 2);
+            let (other_left_idx, other_right_idx) = (2 * other_idx + 1, 2 * other_idx + 2);
+            if left_idx < max_idx && other_left_idx < max_idx {
+                swap_idxs.push((left_idx, other_left_idx));
+            }
+            if right_idx <= max_idx && other_right_idx <= max_idx {
+                swap_idxs.push((right_idx, other_right_idx));
+            }
+        }
+        self.repair(rng)?;
+        other.repair(rng)?;
+        self.validate()?;
+        other.validate()
     }
 
-    fn get_terminal_idxs(&mut self) -> Vec<usize> {
+    fn get_terminal_idxs(&self) -> Vec<usize> {
         let mut terminals: Vec<usize> = Vec::new();
         for (i, node) in self.heap.iter().enumerate() {
             match node {
-                Some(Node::Variable) | Some(Node::Number(_)) => terminals.push(i),
+                Some(Node::Variable(_)) | Some(Node::Number(_)) => terminals.push(i),
                 _ => ()
             };
         }
         terminals
     }
 
-    fn get_op_idxs(&mut self) -> Vec<usize> {
+    fn get_op_idxs(&self) -> Vec<usize> {
         let mut ops: Vec<usize> = Vec::new();
         for (i, node) in self.heap.iter().enumerate() {
             match node {
-                Some(Node::Variable) | Some(Node::Number(_)) | None => (),
+                Some(Node::Variable(_)) | Some(Node::Number(_)) | None => (),
                 _ => ops.push(i)
             };
         }
@@ -451,69 +772,58 @@ impl SymbolicBinaryHeap<f32> {
     }
 
     /// Alter a Node::Number value from a Vec of indicies to terminal nodes
-    fn _mutate_number(&mut self, terminals: &mut Vec<usize>) {
-        assert!(terminals.len() > 0,
-            "Tried to mutate a number without terminals.\nself:\n{}\n", self);
-        let idx = self.rng.gen_range(0, terminals.len());
-        let choice = terminals[idx];
-        let mut num;
-        match self.heap[choice] {
-            Some(Node::Variable) => {
-                self.heap[choice] = Some(Node::<f32>::Variable);
-                return; },
-            Some(Node::Number(n)) => { num = n; },
-            _ => { panic!("Tried to mutate {:?} at {}.\nself:\n{}",
-                self.heap[choice], choice, self) }
+    /// Fails if none of them is a constant
+    fn _mutate_number<R: Rng>(&mut self, terminals: &[usize], rng: &mut R)
+                              -> Result<(), HeapError> {
+        let constants: Vec<usize> = terminals.iter().copied()
+            .filter(|&i| matches!(self.heap[i], Some(Node::Number(_))))
+            .collect();
+        if constants.is_empty() {
+            return Err(HeapError::NoCandidates);
+        }
+        let choice = constants[rng.gen_range(0, constants.len())];
+        let num = match self.heap[choice] {
+            Some(Node::Number(n)) => self.context.perturb_constant(n, rng),
+            _ => return Err(HeapError::BadIndex(choice))
         };
-        let factor = self.rng.gen_range(-1.5, 1.5);
-        let do_add: bool = self.rng.gen();
-        num = if do_add {num + factor} else {num * factor};
-        // "Rebound" with greater error
-        if num > MAX_NUMBER_NODE {
-            num -= 1.2 * (num - MAX_NUMBER_NODE);
-        } else if num < MIN_NUMBER_NODE {
-            num -= 1.2 * (num - MIN_NUMBER_NODE);
-        }
-        num = num.clamp(MIN_NUMBER_NODE, MAX_NUMBER_NODE);
         self._delete_from_idx(choice); // Ensure it has no children
         self.heap[choice] = Some(Node::Number(num));
+        Ok(())
     }
     
     /// Alter a random terminal node with a constant, increasing depth
-    pub fn mutate_constant(&mut self) {
-        let mut terminals: Vec<usize> = self.get_checked_terminals();
+    pub fn mutate_constant<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        let terminals: Vec<usize> = self.get_checked_terminals(rng)?;
         let mut idx: usize;
-        if self.depth() < MAX_DEPTH {
+        if self.depth() < self.max_depth() {
             let mut choice: usize;
             let mut max_spawn_depth: u32;
             let mut iter_terminals = terminals.to_vec();
             for _ in 0..terminals.len() {
-                idx = self.rng.gen_range(0, iter_terminals.len());
+                idx = rng.gen_range(0, iter_terminals.len());
                 choice = iter_terminals.swap_remove(idx);
-                max_spawn_depth = MAX_DEPTH - depth_from_idx(choice) + 1;
+                max_spawn_depth = self.max_depth() - depth_from_idx(choice) + 1;
                 if max_spawn_depth > 2 {
-                    let spawn_depth = self.rng.gen_range(2, max_spawn_depth);
-                    self.random_instantiate(choice, spawn_depth);
-                    return;
+                    let spawn_depth = rng.gen_range(2, max_spawn_depth);
+                    return self.random_instantiate(choice, spawn_depth, rng);
                 }
             }
         }
-        self._mutate_number(&mut terminals);
+        self._mutate_number(&terminals, rng)
     }
     
     /// Replace a random operation node with a terminal node
-    pub fn mutate_clip(&mut self) {
+    pub fn mutate_clip<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
         let ops: Vec<usize> = self.get_op_idxs();
         if ops.len() < 2 {
-            self.random_instantiate(0, 3);
-            return;
+            return self.random_instantiate(0, 3, rng);
         }
-        let choice = ops[self.rng.gen_range(1, ops.len())];
-        self.delete_from_idx(choice);
+        let choice = ops[rng.gen_range(1, ops.len())];
+        self.delete_from_idx(choice, rng)
     }
     
     /// Swap two random branches
-    pub fn mutate_swap(&mut self) {
+    pub fn mutate_swap<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
         // Get parent node indicies
         let mut nodes: Vec<usize> = Vec::new();
         for (i, node) in self.heap.iter().enumerate() {
@@ -522,78 +832,393 @@ impl SymbolicBinaryHeap<f32> {
                 _ => nodes.push(i)
             };
         }
+        if nodes.len() < 3 { // No two branches below the top operation node
+            return self.mutate_constant(rng);
+        }
         nodes.swap_remove(0); // Remove the top operation node
-        let choice1: usize = nodes.swap_remove(self.rng.gen_range(0, nodes.len()));
+        let choice1: usize = nodes.swap_remove(rng.gen_range(0, nodes.len()));
         let choice1_depth = self.heap_at_idx(choice1).depth();
-        let mut choice2: usize = MAX_IDX + 1;
+        let mut choice2: usize = self.max_idx() + 1;
         for _ in 0..nodes.len() {
-            choice2 = nodes.swap_remove(self.rng.gen_range(0, nodes.len()));
+            choice2 = nodes.swap_remove(rng.gen_range(0, nodes.len()));
             let choice2_depth = self.heap_at_idx(choice2).depth();
             if !check_related(choice1, choice2) &&
-                    self.depth() - choice1_depth + choice2_depth <= MAX_DEPTH && 
-                    self.depth() - choice2_depth + choice1_depth <= MAX_DEPTH {
+                    self.depth() - choice1_depth + choice2_depth <= self.max_depth() && 
+                    self.depth() - choice2_depth + choice1_depth <= self.max_depth() {
                 break;
             }
-            choice2 = MAX_IDX + 1;
+            choice2 = self.max_idx() + 1;
         }
-        if choice2 == MAX_IDX + 1 { // Can't swap, so instead mutate a constant
-            self.mutate_constant();
+        if choice2 == self.max_idx() + 1 { // Can't swap, so instead mutate a constant
+            self.mutate_constant(rng)
         } else { // Swap starting at the parent node
             self._swap_from(None, choice1, choice2);
+            Ok(())
         }
     }
     
-    /// Replace a random operation node with a similar operation
-    pub fn mutate_similar(&mut self) {
-        let mut ops: Vec<(usize, Node<f32>)> = Vec::new();
+    /// Replace a random operation node with its similar operation
+    /// Variables are swapped for another available variable
+    pub fn mutate_similar<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        let variables = self.context.variables;
+        let mut ops: Vec<(usize, Node<T>)> = Vec::new();
         for (i, node) in self.heap.iter().enumerate() {
             match node {
-                Some(Node::Add) => ops.push((i, Node::Subtract)),
-                Some(Node::Subtract) => ops.push((i, Node::Add)),
-                Some(Node::Multiply) => ops.push((i, Node::Divide)),
-                Some(Node::Divide) => ops.push((i, Node::Multiply)),
-                Some(Node::Sine) => ops.push((i, Node::Cosine)),
-                Some(Node::Cosine) => ops.push((i, Node::Sine)),
                 Some(Node::Number(n)) => ops.push((i, Node::Number(*n))),
-                _ => ()
+                Some(Node::Variable(v)) if variables > 1 =>
+                    ops.push((i, Node::Variable(*v))),
+                Some(Node::Variable(_)) => (),
+                Some(op) => if let Some(similar) = self.context.primitives.similar(*op) {
+                    ops.push((i, similar))
+                },
+                None => ()
             };
         }
-        let choice: (usize, Node<f32>) = ops[self.rng.gen_range(0, ops.len())];
+        if ops.is_empty() { // Nothing has an enabled partner
+            return self.mutate_constant(rng);
+        }
+        let choice: (usize, Node<T>) = ops[rng.gen_range(0, ops.len())];
         match choice.1 {
-            Node::Add | Node::Subtract | Node::Multiply |
-            Node::Divide | Node::Sine | Node::Cosine =>
-                self.heap[choice.0] = Some(choice.1),
-            Node::Number(_) =>
-                self._mutate_number(&mut vec![choice.0]),
-            _ => ()
+            Node::Number(_) => return self._mutate_number(&[choice.0], rng),
+            Node::Variable(v) => {
+                let other = (v + rng.gen_range(1, variables)) % variables;
+                self.heap[choice.0] = Some(Node::Variable(other)); },
+            op => self.heap[choice.0] = Some(op)
+        };
+        Ok(())
+    }
+
+    /// Change a random terminal node in place
+    /// Variables become a random constant or, with several inputs, another variable
+    /// Constants become a random variable or get Gaussian noise scaled to their magnitude
+    pub fn mutate_terminal<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        let terminals: Vec<usize> = self.get_terminal_idxs();
+        if terminals.is_empty() {
+            return Err(HeapError::NoCandidates);
+        }
+        let choice = terminals[rng.gen_range(0, terminals.len())];
+        let variables = self.context.variables;
+        let node = match self.heap[choice] {
+            Some(Node::Variable(v)) => if variables > 1 && rng.gen() {
+                Node::Variable((v + rng.gen_range(1, variables)) % variables)
+            } else {
+                Node::Number(self.context.random_constant(rng))
+            },
+            Some(Node::Number(n)) => if rng.gen() {
+                Node::Variable(rng.gen_range(0, variables))
+            } else {
+                Node::Number(self.context.jitter_constant(n, rng))
+            },
+            _ => return Err(HeapError::BadIndex(choice))
         };
+        self.heap[choice] = Some(node);
+        Ok(())
+    }
+
+    /// Levels of the subtree rooted at idx, 0 if the slot is empty
+    fn subtree_depth(&self, idx: usize) -> u32 {
+        if idx > self.max_idx() || self.heap[idx].is_none() {
+            return 0;
+        }
+        let left_idx = 2 * idx + 1;
+        let right_idx = left_idx + 1;
+        let left = if left_idx < self.max_idx() {self.subtree_depth(left_idx)} else {0};
+        let right = if right_idx <= self.max_idx() {self.subtree_depth(right_idx)} else {0};
+        1 + left.max(right)
+    }
+
+    /// Indices of the terminals in the subtree rooted at idx
+    fn subtree_terminal_idxs(&self, idx: usize) -> Vec<usize> {
+        let mut terminals: Vec<usize> = Vec::new();
+        let mut idxs: Vec<usize> = vec![idx];
+        while let Some(idx) = idxs.pop() {
+            match self.heap[idx] {
+                Some(Node::Variable(_)) | Some(Node::Number(_)) => terminals.push(idx),
+                Some(_) => {
+                    let left_idx = 2 * idx + 1;
+                    let right_idx = left_idx + 1;
+                    if left_idx < self.max_idx() {
+                        idxs.push(left_idx);
+                    }
+                    if right_idx <= self.max_idx() {
+                        idxs.push(right_idx);
+                    } },
+                None => ()
+            };
+        }
+        terminals
+    }
+
+    /// Replace the heap with the subtree of a random operation node below the top
+    pub fn mutate_hoist<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        let ops: Vec<usize> = self.get_op_idxs();
+        if ops.len() < 2 { // Hoisting a terminal would leave nothing to operate on
+            return self.mutate_constant(rng);
+        }
+        let choice = ops[rng.gen_range(1, ops.len())];
+        self.heap = self.heap_at_idx(choice).heap;
+        Ok(())
+    }
+
+    /// Replace the subtree of a random operation node below the top with one of its terminals
+    pub fn mutate_shrink<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        let ops: Vec<usize> = self.get_op_idxs();
+        if ops.len() < 2 {
+            return self.mutate_constant(rng);
+        }
+        let choice = ops[rng.gen_range(1, ops.len())];
+        let terminals = self.subtree_terminal_idxs(choice);
+        if terminals.is_empty() {
+            return Err(HeapError::MissingOperand(choice));
+        }
+        let terminal = self.heap[terminals[rng.gen_range(0, terminals.len())]];
+        self._delete_from_idx(choice);
+        self.heap[choice] = terminal;
+        Ok(())
+    }
+
+    /// Wrap the subtree of a random node in a new operation node, one level deeper
+    /// A binary operation gets a random terminal as its other operand
+    /// Only subtrees that still fit within max_depth are wrapped
+    pub fn mutate_insert<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        let max_depth = self.max_depth();
+        let nodes: Vec<usize> = (0..self.heap.len())
+            .filter(|&i| self.heap[i].is_some())
+            .filter(|&i| depth_from_idx(i) + self.subtree_depth(i) <= max_depth)
+            .collect();
+        if nodes.is_empty() { // Every branch already reaches max_depth
+            return self.mutate_constant(rng);
+        }
+        let choice = nodes[rng.gen_range(0, nodes.len())];
+        let subtree = self.heap_at_idx(choice);
+        self._delete_from_idx(choice);
+        self.heap[choice] = self.get_op(rng);
+        let left_idx = 2 * choice + 1;
+        let right_idx = left_idx + 1;
+        if self.arity(choice) == 2 && rng.gen() {
+            self.heap[left_idx] = self.get_val(rng);
+            self._swap_from(Some(subtree), right_idx, 0);
+        } else {
+            self._swap_from(Some(subtree), left_idx, 0);
+            if self.arity(choice) == 2 {
+                self.heap[right_idx] = self.get_val(rng);
+            }
+        }
+        Ok(())
+    }
+
+    /// Swap the operands of a random binary operation node
+    pub fn mutate_permutation<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        let ops: Vec<usize> = self.get_op_idxs().into_iter()
+            .filter(|&i| self.arity(i) == 2)
+            .collect();
+        if ops.is_empty() {
+            return self.mutate_constant(rng);
+        }
+        let choice = ops[rng.gen_range(0, ops.len())];
+        self._swap_from(None, 2 * choice + 1, 2 * choice + 2);
+        Ok(())
     }
     
-    /// Recurses into child nodes to determine heap's result for variable
-    fn _collapse(&mut self, idx: usize, variable: f32) -> f32{
+    /// Recurses into child nodes to determine heap's result for the input variables
+    fn _collapse(&self, idx: usize, variables: &[T]) -> T{
         let left_idx = 2 * idx + 1;
         let right_idx = left_idx + 1;
-        let (mut l, mut r) = (f32::MIN_POSITIVE, f32::MIN_POSITIVE);
-        if left_idx < MAX_IDX && self.heap[left_idx].is_some() {
-            l += self._collapse(left_idx, variable);
+        let (mut l, mut r) = (T::MIN_POSITIVE, T::MIN_POSITIVE);
+        if left_idx < self.max_idx() && self.heap[left_idx].is_some() {
+            l += self._collapse(left_idx, variables);
         }
-        if right_idx <= MAX_IDX && self.heap[right_idx].is_some() {
-            r += self._collapse(right_idx, variable);
+        if right_idx <= self.max_idx() && self.heap[right_idx].is_some() {
+            r += self._collapse(right_idx, variables);
         }
         match self.heap[idx] {
-            Some(Node::Add) => l + r,
-            Some(Node::Subtract) => l - r,
-            Some(Node::Multiply) => l * r,
-            Some(Node::Divide) => if r != 0.0 {l / r} else {l / f32::MIN_POSITIVE},
-            Some(Node::Sine) => (l + r).sin(),
-            Some(Node::Cosine) => (l + r).cos(),
             Some(Node::Number(n)) => n,
-            Some(Node::Variable) => variable,
-            _ => 0.0
+            Some(Node::Variable(i)) => variables[i],
+            Some(op) => self.context.primitives.apply(op, l, r),
+            None => T::ZERO
         }
     }
     
-    pub fn collapse(&mut self, variable: f32) -> f32{
-        self._collapse(0, variable)
+    pub fn collapse(&self, variables: &[T]) -> T{
+        self._collapse(0, variables)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate rand;
+
+    use self::rand::SeedableRng;
+    use self::rand::rngs::StdRng;
+
+    use super::*;
+    use super::super::PrimitiveSet;
+
+    fn context() -> Rc<Context<f64>> {
+        let mut context = Context::new(2, PrimitiveSet::all());
+        context.max_depth = 5;
+        Rc::new(context)
+    }
+
+    #[test]
+    fn structural_mutations_stay_valid() {
+        let context = context();
+        let mut rng = StdRng::seed_from_u64(23);
+        for round in 0..400 {
+            let mut heap = SymbolicBinaryHeap::new(&context);
+            heap.spawn(&mut rng).unwrap();
+            let (complexity, depth) = (heap.complexity(), heap.depth());
+            let binary = heap.get_op_idxs().into_iter().any(|i| heap.arity(i) == 2);
+            match round % 4 {
+                0 => {
+                    heap.mutate_hoist(&mut rng).unwrap();
+                    assert!(depth < 3 || heap.depth() < depth); },
+                1 => {
+                    heap.mutate_shrink(&mut rng).unwrap();
+                    assert!(depth < 3 || heap.complexity() < complexity); },
+                2 => {
+                    heap.mutate_insert(&mut rng).unwrap();
+                    assert!(depth == 5 || heap.complexity() > complexity); },
+                _ => {
+                    heap.mutate_permutation(&mut rng).unwrap();
+                    assert!(!binary || heap.complexity() == complexity); }
+            };
+            heap.validate().unwrap();
+            assert!(heap.depth() <= 5);
+        }
+    }
+
+    #[test]
+    fn terminal_mutation_switches_kind() {
+        let context: Rc<Context<f64>> = Rc::new(Context::new(1, PrimitiveSet::all()));
+        let mut rng = StdRng::seed_from_u64(25);
+        let mut heap = SymbolicBinaryHeap::parse("sin(x)", &context).unwrap();
+        heap.mutate_terminal(&mut rng).unwrap();
+        assert!(matches!(heap.heap[1], Some(Node::Number(_))), "{}", heap);
+        let (mut variables, mut numbers) = (0, 0);
+        for _ in 0..100 {
+            let mut heap = SymbolicBinaryHeap::parse("sin(2.5)", &context).unwrap();
+            heap.mutate_terminal(&mut rng).unwrap();
+            heap.validate().unwrap();
+            match heap.heap[1] {
+                Some(Node::Variable(0)) => variables += 1,
+                Some(Node::Number(n)) if n != 2.5 => numbers += 1,
+                node => panic!("{:?} left unchanged or invalid", node)
+            };
+        }
+        assert!(variables > 0 && numbers > 0);
+    }
+
+    #[test]
+    fn permutation_swaps_operands() {
+        let context = context();
+        let mut heap = SymbolicBinaryHeap::parse("x0 - sin(x1)", &context).unwrap();
+        heap.mutate_permutation(&mut StdRng::seed_from_u64(0)).unwrap();
+        assert_eq!(heap.to_string(), "sin(x1) - x0");
+        heap.validate().unwrap();
+    }
+
+    fn laid_out(nodes: &[Option<Node<f64>>], context: &Rc<Context<f64>>)
+                                                            -> SymbolicBinaryHeap<f64> {
+        SymbolicBinaryHeap::new_from(nodes.to_vec(), context).unwrap()
+    }
+
+    #[test]
+    fn validate_reports_each_fault() {
+        let context = context();
+        let (add, x0, two) = (Some(Node::Add), Some(Node::Variable(0)), Some(Node::Number(2.0)));
+        assert_eq!(laid_out(&[add, x0, two], &context).validate(), Ok(()));
+        assert_eq!(laid_out(&[], &context).validate(), Err(HeapError::Empty));
+        assert_eq!(laid_out(&[add, x0], &context).validate(),
+                   Err(HeapError::MissingOperand(0)));
+        assert_eq!(laid_out(&[add, x0, two, two], &context).validate(),
+                   Err(HeapError::Orphan(3)));
+        assert_eq!(laid_out(&[Some(Node::Sine), x0, two], &context).validate(),
+                   Err(HeapError::Orphan(2)));
+        assert_eq!(laid_out(&[add, Some(Node::Variable(2)), two], &context).validate(),
+                   Err(HeapError::VariableOutOfRange(1)));
+        assert_eq!(laid_out(&[Some(Node::Custom(0)), x0, two], &context).validate(),
+                   Err(HeapError::UnknownOperator(0)));
+        let mut spare = laid_out(&[add, x0, two], &context);
+        let max_idx = spare.max_idx();
+        spare.heap[max_idx] = two; // The slot past max_depth
+        assert_eq!(spare.validate(), Err(HeapError::TooDeep(max_idx)));
+    }
+
+    #[test]
+    fn repair_restores_a_valid_layout() {
+        let context = context();
+        let mut rng = StdRng::seed_from_u64(16);
+        let (sine, x0, two) = (Some(Node::Sine), Some(Node::Variable(0)), Some(Node::Number(2.0)));
+        let mut chain = vec![None; 16];
+        for &idx in &[0, 1, 3, 7, 15] { // Down to max_depth, leaving no room for an operand
+            chain[idx] = sine;
+        }
+        let broken = [vec![], vec![Some(Node::Add), x0], vec![Some(Node::Add), x0, two, two],
+                      vec![sine, None, x0], chain];
+        for nodes in broken.iter() {
+            let mut heap = laid_out(nodes, &context);
+            assert!(heap.validate().is_err());
+            assert_eq!(heap.repair(&mut rng), Ok(true));
+            heap.validate().unwrap();
+            assert_eq!(heap.repair(&mut rng), Ok(false));
+        }
+        let mut valid = SymbolicBinaryHeap::parse("x0 * sin(x1)", &context).unwrap();
+        assert_eq!(valid.repair(&mut rng), Ok(false));
+        assert_eq!(valid.to_string(), "x0 * sin(x1)");
+    }
+
+    #[test]
+    fn out_of_range_requests_are_errors() {
+        let context = context();
+        let mut rng = StdRng::seed_from_u64(0);
+        let slots = length_from_depth(context.max_depth) + 1;
+        let mut nodes = vec![None; slots + 3];
+        assert_eq!(SymbolicBinaryHeap::new_from(nodes.clone(), &context).unwrap().heap.len(),
+                   slots);
+        nodes[slots + 1] = Some(Node::Variable(0));
+        assert_eq!(SymbolicBinaryHeap::new_from(nodes, &context).unwrap_err(),
+                   HeapError::TooDeep(slots + 1));
+        let mut heap = SymbolicBinaryHeap::parse("x0 + x1", &context).unwrap();
+        assert_eq!(*heap.left(0).unwrap(), Some(Node::Variable(0)));
+        assert_eq!(*heap.parent(2).unwrap(), Some(Node::Add));
+        assert_eq!(heap.left(16).unwrap_err(), HeapError::BadIndex(16));
+        assert_eq!(heap.right(15).unwrap_err(), HeapError::BadIndex(15));
+        assert_eq!(heap.parent(0).unwrap_err(), HeapError::BadIndex(0));
+        assert_eq!(heap.parent(slots).unwrap_err(), HeapError::BadIndex(slots));
+        let get_val: fn(&SymbolicBinaryHeap<f64>, &mut StdRng) -> Option<Node<f64>> =
+            SymbolicBinaryHeap::get_val;
+        assert_eq!(heap.fill_node(16, get_val, get_val, &mut rng),
+                   Err(HeapError::BadIndex(16)));
+        assert_eq!(heap.random_instantiate(16, 2, &mut rng),
+                   Err(HeapError::BadDepth {idx: 16, depth: 2}));
+        assert_eq!(heap.to_string(), "x0 + x1");
+    }
+
+    #[test]
+    fn constant_mutation_needs_a_constant() {
+        let context = context();
+        let mut rng = StdRng::seed_from_u64(25);
+        let mut full = SymbolicBinaryHeap::parse("sin(sin(sin(sin(x0))))", &context).unwrap();
+        assert_eq!(full.mutate_constant(&mut rng), Err(HeapError::NoCandidates));
+        assert_eq!(full.to_string(), "sin(sin(sin(sin(x0))))");
+        let mut mixed = SymbolicBinaryHeap::parse("sin(sin(sin(x0 + 2)))", &context).unwrap();
+        mixed.mutate_constant(&mut rng).unwrap();
+        assert_eq!(mixed.heap[15], Some(Node::Variable(0)));
+        assert_ne!(mixed.heap[16], Some(Node::Number(2.0)));
+        assert!(matches!(mixed.heap[16], Some(Node::Number(_))));
+    }
+
+    #[test]
+    fn extreme_constants_print_in_scientific_notation() {
+        let context = context();
+        for &(expr, shown) in [("x0 * 1e-300", "x0 * 1e-300"), ("x0 + 6.02e23", "x0 + 6.02e23"),
+                               ("x0 - 2.5e-5", "x0 - 2.5e-5"), ("x0 + 0.00025", "x0 + 0.00025"),
+                               ("x0 * 1234.5", "x0 * 1234.5"), ("-(1e20)", "-(1e20)")].iter() {
+            let heap = SymbolicBinaryHeap::parse(expr, &context).unwrap();
+            assert_eq!(heap.to_string(), shown);
+            let reread = SymbolicBinaryHeap::parse(&heap.to_string(), &context).unwrap();
+            assert_eq!(reread.heap, heap.heap);
+        }
     }
 }
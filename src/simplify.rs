@@ -0,0 +1,135 @@
+use super::{ArenaTree, Expr, Float, Node, PrimitiveSet, SymbolicBinaryHeap};
+
+/// Evaluates an operator on constant operands exactly as SymbolicBinaryHeap::collapse does,
+/// padding each present operand with T::MIN_POSITIVE
+/// Returns None when the result isn't finite, so the expression is kept as is
+fn fold<T: Float>(primitives: &PrimitiveSet<T>, op: Node<T>, l: T, r: Option<T>) -> Option<T> {
+    let (l, r) = match r {
+        Some(r) => (l + T::MIN_POSITIVE, r + T::MIN_POSITIVE),
+        None => (l + T::MIN_POSITIVE, T::MIN_POSITIVE)
+    };
+    let n = primitives.apply(op, l, r);
+    if n.is_finite() {Some(n)} else {None}
+}
+
+fn is_number<T: Float>(expr: &Expr<T>, value: T) -> bool {
+    *expr == Expr::number(value)
+}
+
+impl<T: Float> Expr<T> {
+    /// Returns an equivalent expression that is never deeper, simplifying bottom up with
+    ///   - constant folding: (3.1 * 0.5) => 1.55, sin(2) => 0.9092974
+    ///   - identities: x + 0, x - 0, x * 1, x / 1 => x and x ^ 0 => 1
+    ///   - annihilators: x * 0, 0 / x => 0
+    ///   - self-cancellation: x - x => 0 and x / x => 1, the latter differing where x = 0
+    ///   - negation: -(-x) => x, x - -y => x + y and x + -y => x - y
+    pub fn simplify(self, primitives: &PrimitiveSet<T>) -> Self {
+        match self {
+            Expr::Terminal(_) => self,
+            Expr::Unary(op, operand) => {
+                let operand = operand.simplify(primitives);
+                if let Expr::Terminal(Node::Number(n)) = operand {
+                    if let Some(n) = fold(primitives, op, n, None) {
+                        return Expr::number(n);
+                    }
+                }
+                match (op, operand) {
+                    (Node::Negate, Expr::Unary(Node::Negate, inner)) => *inner,
+                    (op, operand) => Expr::unary(op, operand)
+                } },
+            Expr::Binary(op, left, right) => {
+                let (left, right) = (left.simplify(primitives), right.simplify(primitives));
+                if let (Expr::Terminal(Node::Number(l)), Expr::Terminal(Node::Number(r)))
+                        = (&left, &right) {
+                    if let Some(n) = fold(primitives, op, *l, Some(*r)) {
+                        return Expr::number(n);
+                    }
+                }
+                let (zero, one) = (T::ZERO, T::ONE);
+                match op {
+                    Node::Add if is_number(&left, zero) => right,
+                    Node::Add | Node::Subtract if is_number(&right, zero) => left,
+                    Node::Subtract if left == right => Expr::number(zero),
+                    Node::Multiply if is_number(&left, zero) || is_number(&right, zero) =>
+                        Expr::number(zero),
+                    Node::Multiply if is_number(&left, one) => right,
+                    Node::Multiply | Node::Divide if is_number(&right, one) => left,
+                    Node::Divide if is_number(&left, zero) => Expr::number(zero),
+                    Node::Divide if left == right => Expr::number(one),
+                    Node::Pow if is_number(&right, zero) => Expr::number(one),
+                    Node::Add | Node::Subtract => match right {
+                        Expr::Unary(Node::Negate, inner) => {
+                            let op = if op == Node::Add {Node::Subtract} else {Node::Add};
+                            Expr::Binary(op, Box::new(left), inner) },
+                        right => Expr::binary(op, left, right)
+                    },
+                    _ => Expr::binary(op, left, right)
+                } }
+        }
+    }
+}
+
+impl<T: Float> SymbolicBinaryHeap<T> {
+    /// Simplifies the expression in place, see Expr::simplify, then rebuilds the heap layout
+    /// Useful on a final champion, or periodically to free depth for the search
+    pub fn simplify(&mut self) {
+        let expr = self.to_expr().simplify(&self.context.primitives);
+        // Simplification never deepens an expression, so it always fits
+        self.heap = SymbolicBinaryHeap::from_expr(&expr, &self.context).unwrap().heap;
+    }
+}
+
+impl<T: Float> ArenaTree<T> {
+    /// Simplifies the expression in place, see Expr::simplify
+    pub fn simplify(&mut self) {
+        let expr = self.to_expr().simplify(&self.context.primitives);
+        self.nodes = ArenaTree::from_expr(&expr, &self.context).nodes;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use super::*;
+    use super::super::Context;
+
+    fn simplified(s: &str) -> (SymbolicBinaryHeap<f64>, SymbolicBinaryHeap<f64>) {
+        let context = Rc::new(Context::new(2, PrimitiveSet::all()));
+        let heap = SymbolicBinaryHeap::parse(s, &context).unwrap();
+        let mut simplified = heap.clone();
+        simplified.simplify();
+        (heap, simplified)
+    }
+
+    #[test]
+    fn rewrites_known_forms() {
+        let cases = [("x0 * 1 + 0", "x0"), ("(3 * 0.5) * x1", "1.5 * x1"), ("x0 - x0", "0"),
+                     ("0 / (x0 + 3)", "0"), ("x1 ^ 0", "1"), ("x0 - -x1", "x0 + x1"),
+                     ("x0 + -x1", "x0 - x1"), ("-(-x0)", "x0")];
+        for &(s, expected) in &cases {
+            assert_eq!(simplified(s).1.to_string(), expected, "{} simplified", s);
+        }
+    }
+
+    #[test]
+    fn keeps_values_and_never_deepens() {
+        let expressions = ["x0 * 1 + 0 * x1", "(3.1 * 0.5) * sin(x0) + 2 * 3",
+                           "(x0 + x1) - (x0 + x1) + cos(x1 / 1)", "x0 / x0 + x1 ^ 0",
+                           "exp(0 / (x0 + 3)) - -x1", "(x0 + -x1) * (1 * x0)",
+                           "-(-(x0 * x1)) + log(2)"];
+        for s in &expressions {
+            let (heap, simplified) = simplified(s);
+            assert!(simplified.depth() <= heap.depth(), "{} deepened to {}", s, simplified);
+            for &x0 in &[-2.5, -1.0, 0.5, 3.0] {
+                for &x1 in &[-1.5, 0.25, 2.0] {
+                    let expected = heap.collapse(&[x0, x1]);
+                    let actual = simplified.collapse(&[x0, x1]);
+                    assert!((expected - actual).abs() <= 1e-9 * expected.abs().max(1.0),
+                            "{} is {} at ({}, {}) but {} gives {}",
+                            s, expected, x0, x1, simplified, actual);
+                }
+            }
+        }
+    }
+}
@@ -1,15 +1,22 @@
 extern crate rand;
 extern crate indicatif;
 
-use self::rand::{thread_rng, Rng};
+use self::rand::{thread_rng, Rng, SeedableRng};
+use self::rand::rngs::StdRng;
 use self::indicatif::ProgressIterator;
-use helper::print_vec;
+
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::rc::Rc;
 
 use super::*;
 use crate::individual::Individual;
-use crate::sbh::get_val;
 
-pub struct Simulation {
+/// Times a failed cross-over or mutation is tried again before the parents are kept as they are
+const ATTEMPTS: usize = 3;
+
+/// Evolves a population of expressions in the representation D, see ExpressionTree
+pub struct Simulation<T, D = SymbolicBinaryHeap<T>> {
     iterations: usize,
 
     crossover_probability: f64,
@@ -17,98 +24,207 @@ pub struct Simulation {
     population_size: usize, 
 
     number_of_points: usize,
-    points: Vec<Point>,
+    points: Vec<Point<T>>,
+    context: Rc<Context<T>>,
 
     evaluations: usize,
     number_of_mutations: usize,
     number_of_crossovers: usize,
+    number_of_failures: usize,
 
-    pub champion: Individual
+    /// Simplify every individual each this many iterations, never when 0
+    pub simplify_every: usize,
+    /// Levenberg–Marquardt steps tuning constants, of the champion at the end of run
+    /// and of the fittest individuals every optimize_every iterations, none when 0
+    pub optimize_iterations: usize,
+    /// Optimize constants of the optimize_top fittest individuals each this many iterations,
+    /// never when 0
+    pub optimize_every: usize,
+    pub optimize_top: usize,
+    /// How the initial population is grown
+    pub initialization: Initialization,
+    /// Give every initial individual a variable, see ExpressionTree::ensure_variable
+    pub require_variable: bool,
+    /// Share of the initial population copied from the trees add_seeds was given, at most 1
+    pub seed_share: f64,
+    seed_trees: Vec<D>,
+    /// Relative odds of each mutation operator, indexed like Mutation::ALL
+    pub mutation_weights: [f64; MUTATIONS],
+    /// Shift the odds toward operators that improve fitness, see MutationSelector
+    pub adaptive_mutation: bool,
+    mutations: MutationSelector,
+    /// Seeds every random decision of run, so a seed and inputs always give the same output
+    /// Drawn at random by new unless set
+    pub seed: u64,
+    rng: StdRng,
+
+    pub champion: Individual<T, D>,
+    /// Champion fitness after each iteration of the last run
+    pub fitness_history: Vec<T>
 }
 
-impl Simulation {
+impl<T: Float, D: ExpressionTree<T>> Simulation<T, D> {
+    /// Fails unless population_size is a multiple of 10, max_depth is at least 3 and every
+    /// point has the context's input variables
     pub fn new(iterations: usize,
                crossover_probability: f64,
                mutation_probability: f64,
                population_size: usize,
-               points: Vec<Point>) -> Self {
+               points: Vec<Point<T>>,
+               context: Context<T>) -> Result<Self, String> {
                 
-        assert_eq!(population_size % 10, 0,
-                   "population_size:{} should be divisible by 10", population_size);
+        if !population_size.is_multiple_of(10) {
+            return Err(format!("population_size:{} should be divisible by 10",
+                               population_size));
+        }
+        if context.max_depth < 3 {
+            return Err(format!("max_depth:{} should be at least 3", context.max_depth));
+        }
+        if let Some(i) = points.iter().position(|point| point.dimensions() != context.variables) {
+            return Err(format!("Point {} has {} input variables rather than {}",
+                               i + 1, points[i].dimensions(), context.variables));
+        }
 
         let number_of_points = points.len();
         let evaluations = 0;
         let number_of_mutations = 0;
         let number_of_crossovers = 0;
-        let champion = Individual::new(&points); 
+        let context = Rc::new(context);
+        let seed: u64 = thread_rng().gen();
+        let mut rng = StdRng::seed_from_u64(seed);
+        let champion = Individual::new(&context, &points, &mut rng)
+            .map_err(|err| format!("The champion can't be spawned.\nerror: {}", err))?;
 
-        Simulation { 
+        Ok(Simulation { 
             iterations, 
             crossover_probability, 
             mutation_probability, 
             population_size, 
             number_of_points, 
             points,
+            context,
             evaluations,
             number_of_mutations,
             number_of_crossovers,
-            champion
+            number_of_failures: 0,
+            simplify_every: 0,
+            optimize_iterations: 50,
+            optimize_every: 0,
+            optimize_top: 1,
+            initialization: Initialization::Random,
+            require_variable: false,
+            seed_share: 0.1,
+            seed_trees: Vec::new(),
+            mutation_weights: [1.0; MUTATIONS],
+            adaptive_mutation: false,
+            mutations: MutationSelector::new([1.0; MUTATIONS], false).unwrap(),
+            seed,
+            rng,
+            champion,
+            fitness_history: Vec::new()
+        })
+    }
+
+    fn generate_children(&mut self, mom: Individual<T, D>, dad: &mut Individual<T, D>)
+                                                   -> (Individual<T, D>, Individual<T, D>) {
+        if self.rng.gen_bool(self.crossover_probability) {
+            for _ in 0..ATTEMPTS {
+                match mom.clone().cross_over(dad, &self.points, &mut self.rng) {
+                    Ok(children) => {
+                        self.number_of_crossovers += 2;
+                        return children; },
+                    Err(err) => self.log_failure("Cross-over", &err)
+                }
+            }
         }
+        (mom, dad.clone())
     }
 
-    fn generate_children(&mut self, mom: Individual, dad: &mut Individual)
-                                                   -> (Individual, Individual) {
-        if thread_rng().gen_bool(self.crossover_probability) {
-            self.number_of_crossovers += 2;
-            mom.cross_over(dad, &self.points)
-        } else {
-            (mom, dad.clone())
+    fn might_mutate_child(&mut self, child: &mut Individual<T, D>) {
+        if self.rng.gen_bool(self.mutation_probability) {
+            for _ in 0..ATTEMPTS {
+                let mutation = self.mutations.choose(child.dna.depth(), &mut self.rng);
+                let fitness = child.fitness;
+                match child.mutate(mutation, &self.points, &mut self.rng) {
+                    Ok(()) => {
+                        self.mutations.record(mutation, child.fitness > fitness);
+                        self.number_of_mutations += 1;
+                        return; },
+                    Err(err) => self.log_failure("Mutation", &err)
+                }
+            }
+        }
+    }
+
+    /// Adds expressions, such as a partial model, that seed_share of the initial population
+    /// are copied from in turn
+    /// Fails on the first expression reading unknown variables or, with SymbolicBinaryHeap,
+    /// deeper than max_depth
+    pub fn add_seeds(&mut self, expressions: &[Expr<T>]) -> Result<(), String> {
+        for (i, expr) in expressions.iter().enumerate() {
+            if let Some(v) = expr.max_variable().filter(|&v| v >= self.context.variables) {
+                return Err(format!("Seed {} can't be used.\nerror: Variable x{} is out of range \
+                                    for {} input variable(s)", i + 1, v, self.context.variables));
+            }
+            let dna = D::from_expr(expr, &self.context)
+                .and_then(|dna| dna.validate().map(|()| dna).map_err(|err| err.to_string()))
+                .map_err(|err| format!("Seed {} can't be used.\nerror: {}", i + 1, err))?;
+            self.seed_trees.push(dna);
         }
+        Ok(())
     }
 
-    fn might_mutate_child(&mut self, child: &mut Individual) {
-        if thread_rng().gen_bool(self.mutation_probability) {
-            child.mutate(&self.points);
-            self.number_of_mutations += 1;
+    /// Individuals copied in turn from the seed trees, seed_share of the population
+    fn seed_population(&self) -> Vec<Individual<T, D>> {
+        if self.seed_trees.is_empty() {
+            return Vec::new();
         }
+        let share = if self.seed_share > 0.0 {self.seed_share.min(1.0)} else {0.0};
+        let count = (share * self.population_size as f64).round() as usize;
+        self.seed_trees.iter().cycle().take(count)
+            .map(|dna| Individual::new_from(dna.clone(), &self.points))
+            .collect()
     }
 
-    fn check_individual(&self, guy: &mut Individual) { 
-        if guy.dna.heap[0].is_none() || guy.dna.depth() == 1 {
-            guy.dna.random_instantiate(0, 2);
+    /// Reports an operation that failed on stderr, the run carries on without it
+    fn log_failure(&mut self, operation: &str, err: &HeapError) {
+        self.number_of_failures += 1;
+        eprintln!("{} failed: {}", operation, err);
+    }
+
+    /// Makes an individual valid and deeper than a lone terminal, as crossover needs
+    /// Trees that repair can't fix, such as ones reading unknown variables, are replaced
+    fn check_individual(&mut self, guy: &mut Individual<T, D>) {
+        let mut changed = false;
+        if guy.dna.depth() > 1 {
+            match guy.dna.repair(&mut self.rng) {
+                Ok(repaired) => changed = repaired,
+                Err(err) => self.log_failure("Repair", &err)
+            }
+        }
+        if guy.dna.depth() <= 1 || guy.dna.validate().is_err() {
+            match guy.dna.random_grow(2, &mut self.rng) {
+                Ok(()) => changed = true,
+                Err(err) => self.log_failure("Instantiation", &err)
+            }
+        }
+        if changed {
             guy.update_fitness(&self.points);
         }
-        match guy.dna.heap[0] {
-            Some(Node::Add) | Some(Node::Subtract) |
-            Some(Node::Multiply) | Some(Node::Divide) => {
-                if guy.dna.heap[1].is_none() {
-                    guy.dna.heap[1] = get_val();
-                    guy.update_fitness(&self.points);
-                } else if guy.dna.heap[2].is_none() {
-                    guy.dna.heap[2] = get_val();
-                    guy.update_fitness(&self.points);
-                } },
-            Some(Node::Sine) | Some(Node::Cosine) => {
-                if guy.dna.heap[1].is_none() && guy.dna.heap[2].is_none() {
-                    guy.dna.heap[1] = get_val();
-                    guy.update_fitness(&self.points);
-                } },
-            _ => { // Includes Variable and Number(_)
-                panic!("Bad individual:\n{}\n", guy.dna); }
-        }
-    }
-
-    pub fn generate_population(&mut self, mut individuals: Vec<Individual>) -> Vec<Individual> {
+    }
+
+    pub fn generate_population(&mut self, mut individuals: Vec<Individual<T, D>>)
+                                                             -> Vec<Individual<T, D>> {
         assert_eq!(self.population_size % 2, 0,
                    "population_size:{} should be divisible by 2", self.population_size);
         
-        let mut cumulative_weights = get_cumulative_weights(&individuals);
+        let cumulative_weights = get_cumulative_weights(&individuals);
         let mut next_population = Vec::new();
 
         for _ in 0..(self.population_size / 2 ) { // generate two individuals per iteration
-            let (mom_index, dad_index) = select_parents(&mut cumulative_weights);
-            let mut mom: Individual = individuals[mom_index].clone();
-            let dad: &mut Individual = &mut individuals[dad_index];
+            let (mom_index, dad_index) = select_parents(&cumulative_weights, &mut self.rng);
+            let mut mom: Individual<T, D> = individuals[mom_index].clone();
+            let dad: &mut Individual<T, D> = &mut individuals[dad_index];
             // Can't cross over when depth == 1
             self.check_individual(&mut mom);
             self.check_individual(dad);
@@ -122,8 +238,19 @@ impl Simulation {
         next_population
     }
 
+    /// Optimizes the constants of the optimize_top fittest individuals
+    fn optimize_fittest(&self, population: &mut [Individual<T, D>]) {
+        let mut order: Vec<usize> = (0..population.len()).collect();
+        order.sort_by(|&a, &b| {
+            population[b].fitness.partial_cmp(&population[a].fitness).unwrap_or(Ordering::Equal)
+        });
+        for &i in order.iter().take(self.optimize_top) {
+            population[i].optimize_constants(&self.points, self.optimize_iterations);
+        }
+    }
+
     /// Increments self.evaluations by the sum of individual.fitness in population
-    fn update_evaluations(&mut self, population: &[Individual]) {
+    fn update_evaluations(&mut self, population: &[Individual<T, D>]) {
         let mut population_evals: Vec<usize> = vec![0; self.population_size];
         for (i, individual) in population.iter().enumerate() {
             population_evals[i] = individual.evaluations;
@@ -131,13 +258,33 @@ impl Simulation {
         self.evaluations += population_evals.iter().sum::<usize>();
     }
 
-    pub fn run(&mut self, debug_level: usize, skip: usize) {
-        assert!(skip > 0, "skip must be 1 or larger");
-        let mut population = random_population(self.population_size, &self.points);
+    /// Fails before the first iteration if skip is 0, the mutation weights are invalid, or
+    /// the initial population can't be grown
+    pub fn run(&mut self, debug_level: usize, skip: usize) -> Result<(), String> {
+        if skip == 0 {
+            return Err("skip must be 1 or larger".to_string());
+        }
+        self.rng = StdRng::seed_from_u64(self.seed);
+        self.mutations = MutationSelector::new(self.mutation_weights, self.adaptive_mutation)?;
+        let mut population = self.seed_population();
+        let random_size = self.population_size - population.len();
+        population.extend(random_population(random_size, &self.context, &self.points,
+                                            self.initialization, self.require_variable,
+                                            &mut self.rng)
+            .map_err(|err| format!("The initial population can't be grown.\nerror: {}", err))?);
         let mut champion = find_fittest(&population);
+        self.fitness_history.clear();
         for i in (0..self.iterations).progress() {
             self.update_evaluations(&population);
             population = self.generate_population(population);
+            if self.simplify_every > 0 && (i + 1) % self.simplify_every == 0 {
+                for individual in population.iter_mut() {
+                    individual.simplify(&self.points);
+                }
+            }
+            if self.optimize_every > 0 && (i + 1) % self.optimize_every == 0 {
+                self.optimize_fittest(&mut population);
+            }
             let challenger = find_fittest(&population);
             if (i + 1) % skip == 0 {
                 debug_print(debug_level, i + 1, self.evaluations, &population,
@@ -146,8 +293,13 @@ impl Simulation {
             if champion.fitness <= challenger.fitness {
                 champion = challenger;
             }
+            self.fitness_history.push(champion.fitness);
         }
         self.update_evaluations(&population);
+        let unoptimized_fitness = champion.fitness;
+        let evaluations = champion.evaluations;
+        champion.optimize_constants(&self.points, self.optimize_iterations);
+        self.evaluations += champion.evaluations - evaluations;
         self.champion = champion;
         
         let x = self.population_size * self.iterations;
@@ -157,28 +309,162 @@ impl Simulation {
         println!("mutation_probability: {:?}", self.mutation_probability);
         println!("population_size: {:?}", self.population_size);
         println!("number_of_points: {:?}", self.number_of_points);
+        println!("seed: {:?}", self.seed);
         println!("\n---------------\nSTATS\n ---------------");
-        println!("Champion:\n{}", self.champion.dna);
+        println!("Champion:\n{}", self.champion);
+        let simplified = self.champion.model().simplify(&self.context.primitives);
+        println!("Simplified:\n{}", simplified.display(&self.context));
         println!("Fitness Score: {}", self.champion.fitness);
+        if self.optimize_iterations > 0 {
+            println!("Fitness before constant optimization: {}", unoptimized_fitness);
+        }
+        println!("Distinct expressions in the final population: {} of {}",
+                 count_distinct(&population), population.len());
         println!("Total Evaluations: {}", self.evaluations);
         println!("{} mutations out of {} individuals produced", self.number_of_mutations, x);
         println!("{} cross-overs out of {} individuals produced", self.number_of_crossovers, x);
+        println!("{} failed operations were retried or skipped", self.number_of_failures);
+        println!("Mutations (uses, fitness improvements, final probability):");
+        let probabilities = self.mutations.probabilities();
+        for (mutation, p) in Mutation::ALL.iter().zip(&probabilities) {
+            let (uses, improvements) = self.mutations.outcomes(*mutation);
+            println!("  {}: {}, {}, {:.3}", mutation.name(), uses, improvements, p);
+        }
         println!("\n---------------\nEND\n---------------\n");
+        Ok(())
     }
 }
 
-fn debug_print(debug_level: usize, epoch: usize,
-               evaluations: usize, population: &[Individual],
-               champion: &Individual, challenger: &Individual) {
+/// Number of different formulas in a population, by structural hash
+fn count_distinct<T: Float, D: ExpressionTree<T>>(population: &[Individual<T, D>]) -> usize {
+    population.iter()
+              .map(|individual| individual.dna.structural_hash())
+              .collect::<HashSet<u64>>()
+              .len()
+}
+
+fn debug_print<T: Float, D: ExpressionTree<T>>(debug_level: usize, epoch: usize,
+                                               evaluations: usize, population: &[Individual<T, D>],
+                                               champion: &Individual<T, D>,
+                                               challenger: &Individual<T, D>) {
     if debug_level == 1 {
-        println!("{}, {}, {}, {}", epoch, evaluations, champion.fitness, challenger.fitness);
+        println!("{},{},{},{},\"{}\",\"{}\"", epoch, evaluations,
+                 champion.fitness, challenger.fitness, champion, challenger);
     } else if debug_level >= 2 {
         println!("\n\nepoch {}\nevaluations: {}\nchampion fitness: {}\nchallenger fitness: {}",
                     epoch, evaluations, champion.fitness, challenger.fitness);
-        println!("champion:\n{}\nchallenger:\n{}", champion.dna, challenger.dna);
+        println!("champion:\n{}\nchallenger:\n{}", champion, challenger);
+        println!("distinct expressions: {} of {}", count_distinct(population), population.len());
         if debug_level == 3 {
             println!("\n\n---------------\nepoch {} population\n---------------", epoch);
-            print_vec(population);
+            for individual in population {
+                println!("{}: {}", individual.fitness, individual);
+            }
         } 
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn points() -> Vec<Point<f64>> {
+        (0..8).map(|i| {
+            let x = f64::from(i) / 2.0;
+            Point::new(vec![x], 2.0 * x + 1.0)
+        }).collect()
+    }
+
+    fn simulation<D: ExpressionTree<f64>>() -> Simulation<f64, D> {
+        let mut context = Context::new(1, PrimitiveSet::all());
+        context.max_depth = 4;
+        Simulation::new(10, 0.8, 0.2, 20, points(), context).unwrap()
+    }
+
+    fn exprs(sources: &[&str]) -> Vec<Expr<f64>> {
+        let primitives = PrimitiveSet::all();
+        sources.iter().map(|s| parse_expr(s, &primitives).unwrap()).collect()
+    }
+
+    #[test]
+    fn seeds_are_copied_in_turn() {
+        let mut sim: Simulation<f64> = simulation();
+        assert!(sim.seed_population().is_empty());
+        sim.add_seeds(&exprs(&["2 * x + 1", "x - 1"])).unwrap();
+        sim.seed_share = 0.25;
+        let seeded: Vec<String> = sim.seed_population().iter()
+            .map(|guy| guy.dna.to_string())
+            .collect();
+        assert_eq!(seeded.len(), 5);
+        for (i, dna) in seeded.iter().enumerate() {
+            assert_eq!(dna, &seeded[i % 2]);
+        }
+        assert_ne!(seeded[0], seeded[1]);
+        sim.seed_share = 0.0;
+        assert!(sim.seed_population().is_empty());
+        sim.seed_share = 3.0;
+        assert_eq!(sim.seed_population().len(), 20);
+    }
+
+    #[test]
+    fn bad_seeds_are_rejected() {
+        let mut sim: Simulation<f64> = simulation();
+        let err = sim.add_seeds(&exprs(&["x", "x0 + x1"])).unwrap_err();
+        assert!(err.starts_with("Seed 2 can't be used"), "{}", err);
+        let deep = exprs(&["sin(cos(sin(cos(x))))"]);
+        assert!(sim.add_seeds(&deep).is_err());
+        let mut arena_sim: Simulation<f64, ArenaTree<f64>> = simulation();
+        arena_sim.add_seeds(&deep).unwrap();
+        assert_eq!(arena_sim.seed_population()[0].dna.depth(), 5);
+    }
+
+    fn seeded_run<D: ExpressionTree<f64>>(seed: u64) -> Simulation<f64, D> {
+        let context = Context::new(1, PrimitiveSet::all());
+        let mut sim = Simulation::new(30, 0.8, 0.3, 20, points(), context).unwrap();
+        sim.seed = seed;
+        sim.initialization = Initialization::RampedHalfAndHalf;
+        sim.adaptive_mutation = true;
+        sim.optimize_every = 10;
+        sim.run(0, 1).unwrap();
+        sim
+    }
+
+    #[test]
+    fn same_seed_same_run() {
+        let (first, second) = (seeded_run(42), seeded_run(42));
+        assert_same_run::<SymbolicBinaryHeap<f64>>(&first, &second);
+    }
+
+    #[test]
+    fn same_seed_same_arena_run() {
+        let (first, second) = (seeded_run(42), seeded_run(42));
+        assert_same_run::<ArenaTree<f64>>(&first, &second);
+    }
+
+    fn assert_same_run<D: ExpressionTree<f64>>(first: &Simulation<f64, D>,
+                                                second: &Simulation<f64, D>) {
+        assert_eq!(first.champion.to_string(), second.champion.to_string());
+        assert_eq!(first.champion.fitness.to_bits(), second.champion.fitness.to_bits());
+        assert_eq!(first.fitness_history.len(), 30);
+        assert_eq!(first.fitness_history, second.fitness_history);
+        assert_eq!(first.evaluations, second.evaluations);
+    }
+
+    #[test]
+    fn bad_settings_are_errors() {
+        let error = |population_size: usize, max_depth: u32, variables: usize| {
+            let mut context = Context::new(variables, PrimitiveSet::all());
+            context.max_depth = max_depth;
+            Simulation::<f64>::new(10, 0.8, 0.2, population_size, points(), context).err()
+        };
+        assert_eq!(error(20, 3, 1), None);
+        assert!(error(25, 6, 1).unwrap().contains("divisible by 10"));
+        assert!(error(20, 2, 1).unwrap().contains("at least 3"));
+        assert!(error(20, 6, 2).unwrap().starts_with("Point 1 has 1 input variables"));
+        let mut sim: Simulation<f64> = simulation();
+        assert!(sim.run(0, 0).is_err());
+        sim.mutation_weights = [0.0; MUTATIONS];
+        assert!(sim.run(0, 1).is_err());
+        assert!(sim.fitness_history.is_empty());
+    }
+}
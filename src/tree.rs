@@ -0,0 +1,195 @@
+extern crate rand;
+
+use std::fmt;
+use std::rc::Rc;
+use self::rand::Rng;
+
+use super::{Context, Expr, Float, HeapError, Interval, IntervalReport, Point, Program,
+            SymbolicBinaryHeap};
+
+/// Operations shared by the expression tree representations, which Individual and
+/// Simulation are generic over
+/// SymbolicBinaryHeap lays nodes out by index arithmetic in 2^max_depth slots, ArenaTree
+/// keeps only the nodes present, each holding the indices of its operands
+/// SymbolicBinaryHeap never grows deeper than the context's max_depth through these
+/// operations, while ArenaTree may grow deeper within its 2^max_depth - 1 slots' worth
+/// of nodes, see ArenaTree::max_nodes
+pub trait ExpressionTree<T: Float>: Clone + fmt::Display {
+    /// An empty tree sharing the context
+    fn new(context: &Rc<Context<T>>) -> Self;
+    /// Lays out an expression tree, failing if the representation can't hold it
+    fn from_expr(expr: &Expr<T>, context: &Rc<Context<T>>) -> Result<Self, String>;
+    fn context(&self) -> &Rc<Context<T>>;
+    /// Replaces the tree with a random one
+    fn spawn<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError>;
+    /// Replaces the tree with one where every branch reaches depth
+    fn random_full<R: Rng>(&mut self, depth: u32, rng: &mut R) -> Result<(), HeapError>;
+    /// Replaces the tree with one of at most depth levels and an operator at the root
+    fn random_grow<R: Rng>(&mut self, depth: u32, rng: &mut R) -> Result<(), HeapError>;
+    fn has_variable(&self) -> bool;
+    /// Turns a random constant into a variable if the tree has no variable
+    fn ensure_variable<R: Rng>(&mut self, rng: &mut R) -> bool;
+    /// Grows a random terminal into a subtree, or nudges a constant
+    fn mutate_constant<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError>;
+    /// Replaces a random operator below the root with a terminal
+    fn mutate_clip<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError>;
+    /// Swaps two unrelated branches
+    fn mutate_swap<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError>;
+    /// Replaces a random node with its similar partner
+    fn mutate_similar<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError>;
+    /// Replaces the tree with the subtree of an operator below the root
+    fn mutate_hoist<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError>;
+    /// Replaces the subtree of an operator below the root with one of its terminals
+    fn mutate_shrink<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError>;
+    /// Wraps a random subtree in a new operator
+    fn mutate_insert<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError>;
+    /// Swaps the operands of a random binary operator
+    fn mutate_permutation<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError>;
+    /// Changes a random terminal in place
+    fn mutate_terminal<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError>;
+    /// Swaps a random branch of this tree with one of other
+    fn cross_over<R: Rng>(&mut self, other: &mut Self, rng: &mut R) -> Result<(), HeapError>;
+    /// Evaluates the tree for some input variables
+    fn collapse(&self, variables: &[T]) -> T;
+    /// Flattens the tree into a postfix program
+    fn compile(&self) -> Program<T>;
+    /// Evaluates the tree over a box of inputs, see Program::evaluate_interval
+    fn evaluate_interval(&self, domain: &[Interval<T>]) -> IntervalReport<T> {
+        self.compile().evaluate_interval(domain)
+    }
+    /// Tunes the constants against points, returning the passes over them spent
+    fn optimize_constants(&mut self, points: &[Point<T>], iterations: usize) -> usize;
+    /// Simplifies the expression in place, see Expr::simplify
+    fn simplify(&mut self);
+    /// A hash of the canonical form, equal for the same formula in either representation
+    fn structural_hash(&self) -> u64;
+    /// Number of nodes
+    fn complexity(&self) -> u32;
+    /// Levels of the tree, a single terminal has depth 1
+    fn depth(&self) -> u32;
+    /// Makes an invalid tree valid again, returning whether anything changed
+    fn repair<R: Rng>(&mut self, rng: &mut R) -> Result<bool, HeapError>;
+    /// Checks that operators have exactly their operands and nodes belong to the context
+    fn validate(&self) -> Result<(), HeapError>;
+    fn to_expr(&self) -> Expr<T>;
+    /// Node slots held in memory, used or not
+    fn slots(&self) -> usize;
+}
+
+impl<T: Float> ExpressionTree<T> for SymbolicBinaryHeap<T> {
+    fn new(context: &Rc<Context<T>>) -> Self {
+        SymbolicBinaryHeap::new(context)
+    }
+
+    fn from_expr(expr: &Expr<T>, context: &Rc<Context<T>>) -> Result<Self, String> {
+        SymbolicBinaryHeap::from_expr(expr, context)
+    }
+
+    fn context(&self) -> &Rc<Context<T>> {
+        &self.context
+    }
+
+    fn spawn<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        SymbolicBinaryHeap::spawn(self, rng)
+    }
+
+    fn random_full<R: Rng>(&mut self, depth: u32, rng: &mut R) -> Result<(), HeapError> {
+        SymbolicBinaryHeap::random_full(self, depth, rng)
+    }
+
+    fn random_grow<R: Rng>(&mut self, depth: u32, rng: &mut R) -> Result<(), HeapError> {
+        SymbolicBinaryHeap::random_grow(self, depth, rng)
+    }
+
+    fn has_variable(&self) -> bool {
+        SymbolicBinaryHeap::has_variable(self)
+    }
+
+    fn ensure_variable<R: Rng>(&mut self, rng: &mut R) -> bool {
+        SymbolicBinaryHeap::ensure_variable(self, rng)
+    }
+
+    fn mutate_constant<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        SymbolicBinaryHeap::mutate_constant(self, rng)
+    }
+
+    fn mutate_clip<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        SymbolicBinaryHeap::mutate_clip(self, rng)
+    }
+
+    fn mutate_swap<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        SymbolicBinaryHeap::mutate_swap(self, rng)
+    }
+
+    fn mutate_similar<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        SymbolicBinaryHeap::mutate_similar(self, rng)
+    }
+
+    fn mutate_hoist<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        SymbolicBinaryHeap::mutate_hoist(self, rng)
+    }
+
+    fn mutate_shrink<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        SymbolicBinaryHeap::mutate_shrink(self, rng)
+    }
+
+    fn mutate_insert<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        SymbolicBinaryHeap::mutate_insert(self, rng)
+    }
+
+    fn mutate_permutation<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        SymbolicBinaryHeap::mutate_permutation(self, rng)
+    }
+
+    fn mutate_terminal<R: Rng>(&mut self, rng: &mut R) -> Result<(), HeapError> {
+        SymbolicBinaryHeap::mutate_terminal(self, rng)
+    }
+
+    fn cross_over<R: Rng>(&mut self, other: &mut Self, rng: &mut R) -> Result<(), HeapError> {
+        SymbolicBinaryHeap::cross_over(self, other, rng)
+    }
+
+    fn collapse(&self, variables: &[T]) -> T {
+        SymbolicBinaryHeap::collapse(self, variables)
+    }
+
+    fn compile(&self) -> Program<T> {
+        SymbolicBinaryHeap::compile(self)
+    }
+
+    fn optimize_constants(&mut self, points: &[Point<T>], iterations: usize) -> usize {
+        SymbolicBinaryHeap::optimize_constants(self, points, iterations)
+    }
+
+    fn simplify(&mut self) {
+        SymbolicBinaryHeap::simplify(self)
+    }
+
+    fn structural_hash(&self) -> u64 {
+        SymbolicBinaryHeap::structural_hash(self)
+    }
+
+    fn complexity(&self) -> u32 {
+        SymbolicBinaryHeap::complexity(self)
+    }
+
+    fn depth(&self) -> u32 {
+        SymbolicBinaryHeap::depth(self)
+    }
+
+    fn repair<R: Rng>(&mut self, rng: &mut R) -> Result<bool, HeapError> {
+        SymbolicBinaryHeap::repair(self, rng)
+    }
+
+    fn validate(&self) -> Result<(), HeapError> {
+        SymbolicBinaryHeap::validate(self)
+    }
+
+    fn to_expr(&self) -> Expr<T> {
+        SymbolicBinaryHeap::to_expr(self)
+    }
+
+    fn slots(&self) -> usize {
+        self.heap.capacity()
+    }
+}